@@ -1 +0,0 @@
-DROP TABLE maintenance_jobs
\ No newline at end of file
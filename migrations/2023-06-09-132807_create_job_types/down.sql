@@ -1 +0,0 @@
--- This file should undo anything in `up.sql`
\ No newline at end of file
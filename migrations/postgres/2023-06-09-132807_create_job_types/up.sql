@@ -0,0 +1,7 @@
+CREATE TABLE jobs (
+    id SERIAL PRIMARY KEY,
+    name VARCHAR NOT NULL,
+    version VARCHAR,
+    docker_image VARCHAR NOT NULL,
+    docker_image_tag VARCHAR NOT NULL
+)
@@ -0,0 +1,13 @@
+CREATE TABLE maintenances (
+    id SERIAL PRIMARY KEY,
+    uuid VARCHAR UNIQUE NOT NULL,
+    name VARCHAR,
+    created_at TIMESTAMP DEFAULT (CURRENT_TIMESTAMP AT TIME ZONE 'UTC') NOT NULL,
+    updated_at TIMESTAMP,
+    failed_attempts INT NOT NULL,
+    status VARCHAR NOT NULL,
+    scheduled_for TIMESTAMP,
+    downtime_window_start TIMESTAMP,
+    downtime_window_end TIMESTAMP,
+    job_id INTEGER REFERENCES jobs(id) NOT NULL
+)
@@ -0,0 +1,2 @@
+ALTER TABLE maintenances DROP COLUMN window_overrun;
+ALTER TABLE jobs DROP COLUMN estimated_duration;
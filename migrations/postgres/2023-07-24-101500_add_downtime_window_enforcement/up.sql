@@ -0,0 +1,2 @@
+ALTER TABLE jobs ADD COLUMN estimated_duration INTEGER;
+ALTER TABLE maintenances ADD COLUMN window_overrun BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,14 @@
+ALTER TABLE maintenance_runs DROP COLUMN failure_reason;
+ALTER TABLE maintenance_runs DROP COLUMN exit_code;
+ALTER TABLE maintenance_runs DROP COLUMN conditions;
+ALTER TABLE maintenance_runs DROP COLUMN started_at;
+ALTER TABLE maintenance_runs DROP COLUMN k8s_job_name;
+ALTER TABLE maintenance_runs DROP COLUMN worker_id;
+UPDATE maintenance_runs SET finished_at = CURRENT_TIMESTAMP WHERE finished_at IS NULL;
+ALTER TABLE maintenance_runs ALTER COLUMN finished_at SET DEFAULT CURRENT_TIMESTAMP;
+ALTER TABLE maintenance_runs ALTER COLUMN finished_at SET NOT NULL;
+ALTER TABLE maintenance_runs ADD COLUMN failed_attempts INTEGER;
+UPDATE maintenance_runs
+SET failed_attempts = CASE WHEN status = 'failed' THEN attempt ELSE attempt - 1 END;
+ALTER TABLE maintenance_runs ALTER COLUMN failed_attempts SET NOT NULL;
+ALTER TABLE maintenance_runs DROP COLUMN attempt;
@@ -0,0 +1,13 @@
+ALTER TABLE maintenance_runs ADD COLUMN attempt INTEGER;
+UPDATE maintenance_runs
+SET attempt = CASE WHEN status = 'failed' THEN failed_attempts ELSE failed_attempts + 1 END;
+ALTER TABLE maintenance_runs ALTER COLUMN attempt SET NOT NULL;
+ALTER TABLE maintenance_runs DROP COLUMN failed_attempts;
+ALTER TABLE maintenance_runs ALTER COLUMN finished_at DROP NOT NULL;
+ALTER TABLE maintenance_runs ALTER COLUMN finished_at DROP DEFAULT;
+ALTER TABLE maintenance_runs ADD COLUMN worker_id VARCHAR;
+ALTER TABLE maintenance_runs ADD COLUMN k8s_job_name VARCHAR;
+ALTER TABLE maintenance_runs ADD COLUMN started_at TIMESTAMP;
+ALTER TABLE maintenance_runs ADD COLUMN conditions JSON;
+ALTER TABLE maintenance_runs ADD COLUMN exit_code INTEGER;
+ALTER TABLE maintenance_runs ADD COLUMN failure_reason VARCHAR;
@@ -0,0 +1,4 @@
+ALTER TABLE jobs ADD COLUMN active_deadline_seconds INTEGER;
+ALTER TABLE jobs ADD COLUMN wait_timeout INTEGER;
+ALTER TABLE maintenances ADD COLUMN active_deadline_seconds INTEGER;
+ALTER TABLE maintenances ADD COLUMN wait_timeout INTEGER;
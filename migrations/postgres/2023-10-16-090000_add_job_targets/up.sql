@@ -0,0 +1,4 @@
+ALTER TABLE jobs ADD COLUMN cluster VARCHAR;
+ALTER TABLE jobs ADD COLUMN namespace VARCHAR;
+ALTER TABLE maintenances ADD COLUMN cluster VARCHAR;
+ALTER TABLE maintenances ADD COLUMN namespace VARCHAR;
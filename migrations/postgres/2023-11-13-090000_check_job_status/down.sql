@@ -0,0 +1,2 @@
+ALTER TABLE maintenance_runs DROP CONSTRAINT maintenance_runs_status_check;
+ALTER TABLE maintenances DROP CONSTRAINT maintenances_status_check;
@@ -0,0 +1,8 @@
+ALTER TABLE maintenances ADD CONSTRAINT maintenances_status_check CHECK (status IN (
+    'pending', 'queued', 'waiting_for_window', 'running', 'succeeded',
+    'failed', 'timed_out', 'cancelled', 'window_missed'
+));
+ALTER TABLE maintenance_runs ADD CONSTRAINT maintenance_runs_status_check CHECK (status IN (
+    'pending', 'queued', 'waiting_for_window', 'running', 'succeeded',
+    'failed', 'timed_out', 'cancelled', 'window_missed'
+));
@@ -0,0 +1,2 @@
+DROP INDEX maintenances_ready;
+ALTER TABLE maintenances DROP COLUMN priority;
@@ -0,0 +1,5 @@
+ALTER TABLE maintenances ADD COLUMN priority INTEGER NOT NULL DEFAULT 0;
+CREATE INDEX maintenances_ready ON maintenances (status, scheduled_for);
+-- maintenances used to be inserted without a creation time
+UPDATE maintenances SET created_at = COALESCE(updated_at, scheduled_for, CURRENT_TIMESTAMP)
+WHERE created_at < '1970-01-02';
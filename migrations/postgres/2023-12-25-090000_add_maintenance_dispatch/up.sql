@@ -0,0 +1 @@
+ALTER TABLE maintenances ADD COLUMN dispatched_at TIMESTAMP;
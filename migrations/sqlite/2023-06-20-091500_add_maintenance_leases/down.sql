@@ -0,0 +1,2 @@
+ALTER TABLE maintenances DROP COLUMN lease_expires_at;
+ALTER TABLE maintenances DROP COLUMN worker_id;
@@ -0,0 +1,2 @@
+ALTER TABLE maintenances ADD COLUMN worker_id VARCHAR;
+ALTER TABLE maintenances ADD COLUMN lease_expires_at DATETIME;
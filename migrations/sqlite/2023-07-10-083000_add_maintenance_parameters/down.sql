@@ -0,0 +1,2 @@
+ALTER TABLE jobs DROP COLUMN parameters_schema;
+ALTER TABLE maintenances DROP COLUMN parameters;
@@ -0,0 +1,2 @@
+ALTER TABLE maintenances ADD COLUMN parameters TEXT;
+ALTER TABLE jobs ADD COLUMN parameters_schema TEXT;
@@ -0,0 +1,2 @@
+ALTER TABLE maintenances DROP COLUMN window_id;
+DROP TABLE maintenance_windows;
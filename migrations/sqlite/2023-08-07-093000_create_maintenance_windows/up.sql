@@ -0,0 +1,8 @@
+CREATE TABLE maintenance_windows (
+    id INTEGER PRIMARY KEY NOT NULL,
+    name VARCHAR UNIQUE NOT NULL,
+    schedule VARCHAR NOT NULL,
+    time_zone VARCHAR NOT NULL,
+    duration INTEGER NOT NULL
+);
+ALTER TABLE maintenances ADD COLUMN window_id INTEGER;
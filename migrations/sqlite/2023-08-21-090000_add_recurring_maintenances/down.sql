@@ -0,0 +1,2 @@
+DROP TABLE maintenance_runs;
+ALTER TABLE maintenances DROP COLUMN recurrence;
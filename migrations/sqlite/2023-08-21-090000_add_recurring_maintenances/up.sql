@@ -0,0 +1,10 @@
+ALTER TABLE maintenances ADD COLUMN recurrence VARCHAR;
+CREATE TABLE maintenance_runs (
+    id INTEGER PRIMARY KEY NOT NULL,
+    maintenance_uuid VARCHAR NOT NULL,
+    status VARCHAR NOT NULL,
+    scheduled_for DATETIME,
+    finished_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    failed_attempts INTEGER NOT NULL
+);
+CREATE INDEX maintenance_runs_maintenance_uuid ON maintenance_runs (maintenance_uuid);
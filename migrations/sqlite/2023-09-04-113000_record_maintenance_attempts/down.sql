@@ -0,0 +1,15 @@
+CREATE TABLE maintenance_occurrences (
+    id INTEGER PRIMARY KEY NOT NULL,
+    maintenance_uuid VARCHAR NOT NULL,
+    status VARCHAR NOT NULL,
+    scheduled_for DATETIME,
+    finished_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    failed_attempts INTEGER NOT NULL
+);
+INSERT INTO maintenance_occurrences (id, maintenance_uuid, status, scheduled_for, finished_at, failed_attempts)
+SELECT id, maintenance_uuid, status, scheduled_for, COALESCE(finished_at, CURRENT_TIMESTAMP),
+       CASE WHEN status = 'failed' THEN attempt ELSE attempt - 1 END
+FROM maintenance_runs;
+DROP TABLE maintenance_runs;
+ALTER TABLE maintenance_occurrences RENAME TO maintenance_runs;
+CREATE INDEX maintenance_runs_maintenance_uuid ON maintenance_runs (maintenance_uuid);
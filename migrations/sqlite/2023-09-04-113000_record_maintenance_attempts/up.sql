@@ -0,0 +1,22 @@
+CREATE TABLE maintenance_attempts (
+    id INTEGER PRIMARY KEY NOT NULL,
+    maintenance_uuid VARCHAR NOT NULL,
+    attempt INTEGER NOT NULL,
+    status VARCHAR NOT NULL,
+    scheduled_for DATETIME,
+    worker_id VARCHAR,
+    k8s_job_name VARCHAR,
+    started_at DATETIME,
+    finished_at DATETIME,
+    conditions TEXT,
+    exit_code INTEGER,
+    failure_reason VARCHAR
+);
+INSERT INTO maintenance_attempts (id, maintenance_uuid, attempt, status, scheduled_for, finished_at)
+SELECT id, maintenance_uuid,
+       CASE WHEN status = 'failed' THEN failed_attempts ELSE failed_attempts + 1 END,
+       status, scheduled_for, finished_at
+FROM maintenance_runs;
+DROP TABLE maintenance_runs;
+ALTER TABLE maintenance_attempts RENAME TO maintenance_runs;
+CREATE INDEX maintenance_runs_maintenance_uuid ON maintenance_runs (maintenance_uuid);
@@ -0,0 +1 @@
+ALTER TABLE maintenance_runs DROP COLUMN logs;
@@ -0,0 +1 @@
+ALTER TABLE maintenance_runs ADD COLUMN logs TEXT;
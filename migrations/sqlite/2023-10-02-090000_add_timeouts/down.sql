@@ -0,0 +1,4 @@
+ALTER TABLE maintenances DROP COLUMN wait_timeout;
+ALTER TABLE maintenances DROP COLUMN active_deadline_seconds;
+ALTER TABLE jobs DROP COLUMN wait_timeout;
+ALTER TABLE jobs DROP COLUMN active_deadline_seconds;
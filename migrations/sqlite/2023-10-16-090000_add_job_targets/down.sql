@@ -0,0 +1,4 @@
+ALTER TABLE maintenances DROP COLUMN namespace;
+ALTER TABLE maintenances DROP COLUMN cluster;
+ALTER TABLE jobs DROP COLUMN namespace;
+ALTER TABLE jobs DROP COLUMN cluster;
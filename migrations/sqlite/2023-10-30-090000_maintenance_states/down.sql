@@ -0,0 +1,3 @@
+UPDATE maintenance_runs SET status = 'finished' WHERE status = 'succeeded';
+UPDATE maintenances SET status = 'finished' WHERE status = 'succeeded';
+UPDATE maintenances SET status = 'queued' WHERE status IN ('pending', 'waiting_for_window');
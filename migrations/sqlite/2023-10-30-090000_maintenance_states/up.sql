@@ -0,0 +1,7 @@
+UPDATE maintenances SET status = 'pending' WHERE status = 'not_queued';
+UPDATE maintenances SET status = 'succeeded' WHERE status = 'finished';
+UPDATE maintenance_runs SET status = 'succeeded' WHERE status = 'finished';
+UPDATE maintenances SET status = 'waiting_for_window'
+WHERE status = 'queued' AND downtime_window_start > CURRENT_TIMESTAMP;
+UPDATE maintenances SET status = 'pending'
+WHERE status = 'queued' AND failed_attempts = 0 AND scheduled_for > CURRENT_TIMESTAMP;
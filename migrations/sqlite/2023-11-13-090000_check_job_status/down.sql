@@ -0,0 +1,57 @@
+-- SQLite can not drop a constraint of an existing table, so both tables are
+-- rebuilt.
+CREATE TABLE maintenances_new (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    uuid VARCHAR UNIQUE NOT NULL,
+    name VARCHAR,
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    updated_at DATETIME,
+    failed_attempts INT NOT NULL,
+    status VARCHAR NOT NULL,
+    scheduled_for DATETIME,
+    downtime_window_start DATETIME,
+    downtime_window_end DATETIME,
+    job_id INTEGER REFERENCES jobs(id) NOT NULL,
+    worker_id VARCHAR,
+    lease_expires_at DATETIME,
+    parameters TEXT,
+    window_overrun BOOLEAN NOT NULL DEFAULT FALSE,
+    window_id INTEGER,
+    recurrence VARCHAR,
+    active_deadline_seconds INTEGER,
+    wait_timeout INTEGER,
+    cluster VARCHAR,
+    namespace VARCHAR
+);
+INSERT INTO maintenances_new SELECT
+    id, uuid, name, created_at, updated_at, failed_attempts, status,
+    scheduled_for, downtime_window_start, downtime_window_end, job_id,
+    worker_id, lease_expires_at, parameters, window_overrun, window_id,
+    recurrence, active_deadline_seconds, wait_timeout, cluster, namespace
+FROM maintenances;
+DROP TABLE maintenances;
+ALTER TABLE maintenances_new RENAME TO maintenances;
+
+CREATE TABLE maintenance_runs_new (
+    id INTEGER PRIMARY KEY NOT NULL,
+    maintenance_uuid VARCHAR NOT NULL,
+    attempt INTEGER NOT NULL,
+    status VARCHAR NOT NULL,
+    scheduled_for DATETIME,
+    worker_id VARCHAR,
+    k8s_job_name VARCHAR,
+    started_at DATETIME,
+    finished_at DATETIME,
+    conditions TEXT,
+    exit_code INTEGER,
+    failure_reason VARCHAR,
+    logs TEXT
+);
+INSERT INTO maintenance_runs_new SELECT
+    id, maintenance_uuid, attempt, status, scheduled_for, worker_id,
+    k8s_job_name, started_at, finished_at, conditions, exit_code,
+    failure_reason, logs
+FROM maintenance_runs;
+DROP TABLE maintenance_runs;
+ALTER TABLE maintenance_runs_new RENAME TO maintenance_runs;
+CREATE INDEX maintenance_runs_maintenance_uuid ON maintenance_runs (maintenance_uuid);
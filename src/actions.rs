@@ -1,10 +1,15 @@
+use crate::cluster::Target;
+use crate::db::{self, DbConnection};
 use crate::models;
+use crate::queue::ConcurrencyLimits;
+use crate::schedule;
 use diesel::dsl::{delete, insert_into, now, update};
 use diesel::prelude::*;
 use diesel::result::Error as dieselError;
+use std::collections::{HashMap, HashSet};
 
 pub fn get_all_maintenance(
-    conn: &mut SqliteConnection,
+    conn: &mut DbConnection,
 ) -> Result<Option<Vec<models::Maintenance>>, dieselError> {
     use crate::schema::maintenances::dsl::*;
 
@@ -13,7 +18,7 @@ pub fn get_all_maintenance(
 }
 
 pub fn find_maintenance_by_os_uuid(
-    conn: &mut SqliteConnection,
+    conn: &mut DbConnection,
     uid: String,
 ) -> Result<Option<models::Maintenance>, dieselError> {
     use crate::schema::maintenances::dsl::*;
@@ -26,89 +31,1149 @@ pub fn find_maintenance_by_os_uuid(
     Ok(maint)
 }
 
+/// insert_new_maintenance inserts `object`, or replaces the maintenance
+/// with the same uuid if that may go to the status of `object`. It returns
+/// false if the existing maintenance may not, e.g. because it is running.
 pub fn insert_new_maintenance(
-    conn: &mut SqliteConnection,
+    conn: &mut DbConnection,
     object: models::Maintenance,
-) -> Result<models::Maintenance, dieselError> {
+) -> Result<bool, dieselError> {
     use crate::schema::maintenances::dsl::*;
-    insert_into(maintenances)
+    use diesel::query_dsl::methods::FilterDsl;
+    let changes = models::MaintenanceChanges::from(&object);
+    let inserted = insert_into(maintenances)
         .values(&object)
         .on_conflict(uuid)
         .do_update()
-        .set(&object)
-        .get_result(conn)
+        .set((&changes, updated_at.eq(now)))
+        .filter(status.eq_any(models::JobStatus::sources(
+            object.status,
+            models::JobStatus::can_transition_to,
+        )))
+        .execute(conn)?;
+
+    Ok(inserted > 0)
+}
+
+pub fn get_all_jobs(conn: &mut DbConnection) -> Result<Vec<models::Job>, dieselError> {
+    use crate::schema::jobs::dsl::*;
+
+    jobs.order(id.asc()).load::<models::Job>(conn)
+}
+
+pub fn find_job_by_id(
+    conn: &mut DbConnection,
+    job_id: i32,
+) -> Result<Option<models::Job>, dieselError> {
+    use crate::schema::jobs::dsl::*;
+
+    let job = jobs
+        .filter(id.eq(job_id))
+        .first::<models::Job>(conn)
+        .optional()?;
+
+    Ok(job)
 }
 
 pub fn insert_new_job(
-    conn: &mut SqliteConnection,
-    object: models::Job,
+    conn: &mut DbConnection,
+    object: models::NewJob,
 ) -> Result<models::Job, dieselError> {
     use crate::schema::jobs::dsl::*;
-    insert_into(jobs)
+    insert_into(jobs).values(&object).get_result(conn)
+}
+
+pub fn update_job(
+    conn: &mut DbConnection,
+    job_id: i32,
+    object: models::NewJob,
+) -> Result<Option<models::Job>, dieselError> {
+    use crate::schema::jobs::dsl::*;
+    update(jobs)
+        .filter(id.eq(job_id))
+        .set(&object)
+        .get_result(conn)
+        .optional()
+}
+
+pub fn count_maintenances_of_job(
+    conn: &mut DbConnection,
+    job_type: i32,
+) -> Result<i64, dieselError> {
+    use crate::schema::maintenances::dsl::*;
+    maintenances
+        .filter(job_id.eq(job_type))
+        .count()
+        .get_result(conn)
+}
+
+pub fn delete_job(conn: &mut DbConnection, job_id: i32) -> Result<usize, dieselError> {
+    use crate::schema::jobs::dsl::*;
+    delete(jobs).filter(id.eq(job_id)).execute(conn)
+}
+
+pub fn get_all_windows(
+    conn: &mut DbConnection,
+) -> Result<Vec<models::MaintenanceWindow>, dieselError> {
+    use crate::schema::maintenance_windows::dsl::*;
+
+    maintenance_windows
+        .order(id.asc())
+        .load::<models::MaintenanceWindow>(conn)
+}
+
+pub fn find_window_by_id(
+    conn: &mut DbConnection,
+    window: i32,
+) -> Result<Option<models::MaintenanceWindow>, dieselError> {
+    use crate::schema::maintenance_windows::dsl::*;
+
+    maintenance_windows
+        .filter(id.eq(window))
+        .first::<models::MaintenanceWindow>(conn)
+        .optional()
+}
+
+pub fn insert_new_window(
+    conn: &mut DbConnection,
+    object: models::NewMaintenanceWindow,
+) -> Result<models::MaintenanceWindow, dieselError> {
+    use crate::schema::maintenance_windows::dsl::*;
+    insert_into(maintenance_windows)
         .values(&object)
-        .on_conflict((name, docker_image, docker_image_tag))
-        .do_update()
+        .get_result(conn)
+}
+
+pub fn update_window(
+    conn: &mut DbConnection,
+    window: i32,
+    object: models::NewMaintenanceWindow,
+) -> Result<Option<models::MaintenanceWindow>, dieselError> {
+    use crate::schema::maintenance_windows::dsl::*;
+    update(maintenance_windows)
+        .filter(id.eq(window))
         .set(&object)
         .get_result(conn)
+        .optional()
+}
+
+pub fn count_maintenances_of_window(
+    conn: &mut DbConnection,
+    window: i32,
+) -> Result<i64, dieselError> {
+    use crate::schema::maintenances::dsl::*;
+    maintenances
+        .filter(window_id.eq(window))
+        .count()
+        .get_result(conn)
 }
 
-pub fn delete_maintenance(conn: &mut SqliteConnection, uid: String) -> Result<(), dieselError> {
+pub fn delete_window(conn: &mut DbConnection, window: i32) -> Result<usize, dieselError> {
+    use crate::schema::maintenance_windows::dsl::*;
+    delete(maintenance_windows)
+        .filter(id.eq(window))
+        .execute(conn)
+}
+
+/// update_maintenance_status moves a maintenance to `job_status`. It returns
+/// false if the maintenance is not in a state it may go there from.
+pub fn update_maintenance_status(
+    conn: &mut DbConnection,
+    uid: String,
+    job_status: models::JobStatus,
+) -> Result<bool, dieselError> {
     use crate::schema::maintenances::dsl::*;
-    delete(maintenances)
+    let updated = update(maintenances)
         .filter(uuid.eq(uid.to_string()))
+        .filter(status.eq_any(models::JobStatus::sources(
+            job_status,
+            models::JobStatus::can_transition_to,
+        )))
+        .set((
+            status.eq(job_status),
+            updated_at.eq(now),
+            worker_id.eq(None::<String>),
+            lease_expires_at.eq(None::<chrono::NaiveDateTime>),
+        ))
         .execute(conn)?;
 
-    Ok(())
+    Ok(updated > 0)
 }
 
-pub fn delete_all_maintenance(conn: &mut SqliteConnection) -> Result<(), dieselError> {
+/// retry_maintenance records a failed attempt and puts the maintenance back
+/// into the queue, to be picked up again at `retry_at`. It returns false if
+/// the maintenance is not running.
+pub fn retry_maintenance(
+    conn: &mut DbConnection,
+    uid: String,
+    retry_at: chrono::NaiveDateTime,
+) -> Result<bool, dieselError> {
     use crate::schema::maintenances::dsl::*;
-    delete(maintenances).execute(conn)?;
+    let updated = update(maintenances)
+        .filter(uuid.eq(uid.to_string()))
+        .filter(status.eq_any(models::JobStatus::sources(
+            models::JobStatus::Queued,
+            models::JobStatus::can_retry_to,
+        )))
+        .set((
+            status.eq(models::JobStatus::Queued),
+            scheduled_for.eq(retry_at),
+            updated_at.eq(now),
+            failed_attempts.eq(failed_attempts + 1),
+            worker_id.eq(None::<String>),
+            lease_expires_at.eq(None::<chrono::NaiveDateTime>),
+            dispatched_at.eq(None::<chrono::NaiveDateTime>),
+        ))
+        .execute(conn)?;
+
+    Ok(updated > 0)
+}
+
+/// reschedule_maintenance queues a recurring maintenance again for its next
+/// occurrence at `next_at`, within the downtime window `window` if it has
+/// one. The attempts of the previous occurrence are forgotten. It returns
+/// false if the maintenance is neither running nor waiting.
+pub fn reschedule_maintenance(
+    conn: &mut DbConnection,
+    uid: String,
+    next_at: chrono::NaiveDateTime,
+    window: Option<(chrono::NaiveDateTime, chrono::NaiveDateTime)>,
+    next_status: models::JobStatus,
+) -> Result<bool, dieselError> {
+    use crate::schema::maintenances::dsl::*;
+    let updated = update(maintenances)
+        .filter(uuid.eq(uid.to_string()))
+        .filter(status.eq_any(models::JobStatus::sources(
+            next_status,
+            models::JobStatus::can_reschedule_to,
+        )))
+        .set((
+            status.eq(next_status),
+            scheduled_for.eq(next_at),
+            downtime_window_start.eq(window.map(|(start, _)| start)),
+            downtime_window_end.eq(window.map(|(_, end)| end)),
+            updated_at.eq(now),
+            failed_attempts.eq(0),
+            worker_id.eq(None::<String>),
+            lease_expires_at.eq(None::<chrono::NaiveDateTime>),
+            window_overrun.eq(false),
+            dispatched_at.eq(None::<chrono::NaiveDateTime>),
+        ))
+        .execute(conn)?;
+
+    Ok(updated > 0)
+}
+
+/// finish_run records the end of the running attempt of a maintenance.
+pub fn finish_run(
+    conn: &mut DbConnection,
+    uid: String,
+    run_status: models::JobStatus,
+    report: models::RunReport,
+) -> Result<(), dieselError> {
+    use crate::schema::maintenance_runs::dsl::*;
+    update(maintenance_runs)
+        .filter(maintenance_uuid.eq(uid))
+        .filter(finished_at.is_null())
+        .set((status.eq(run_status), finished_at.eq(now), &report))
+        .execute(conn)?;
 
     Ok(())
 }
 
-pub fn update_maintenance_status(
-    conn: &mut SqliteConnection,
+pub fn get_runs_of_maintenance(
+    conn: &mut DbConnection,
+    uid: String,
+) -> Result<Vec<models::MaintenanceRun>, dieselError> {
+    use crate::schema::maintenance_runs::dsl::*;
+    maintenance_runs
+        .filter(maintenance_uuid.eq(uid))
+        .order(id.asc())
+        .load::<models::MaintenanceRun>(conn)
+}
+
+pub fn find_run_of_maintenance(
+    conn: &mut DbConnection,
+    uid: String,
+    run_id: i32,
+) -> Result<Option<models::MaintenanceRun>, dieselError> {
+    use crate::schema::maintenance_runs::dsl::*;
+    maintenance_runs
+        .filter(maintenance_uuid.eq(uid))
+        .filter(id.eq(run_id))
+        .first::<models::MaintenanceRun>(conn)
+        .optional()
+}
+
+/// fail_maintenance records the last failed attempt and moves the maintenance
+/// into the terminal `job_status`, failed or timed out. It returns false if
+/// the maintenance is not running.
+pub fn fail_maintenance(
+    conn: &mut DbConnection,
     uid: String,
     job_status: models::JobStatus,
-) -> Result<(), dieselError> {
+) -> Result<bool, dieselError> {
     use crate::schema::maintenances::dsl::*;
-    update(maintenances)
+    let updated = update(maintenances)
         .filter(uuid.eq(uid.to_string()))
+        .filter(status.eq_any(models::JobStatus::sources(
+            job_status,
+            models::JobStatus::can_transition_to,
+        )))
         .set((
-            status.eq(job_status.to_string()),
+            status.eq(job_status),
             updated_at.eq(now),
             failed_attempts.eq(failed_attempts + 1),
+            worker_id.eq(None::<String>),
+            lease_expires_at.eq(None::<chrono::NaiveDateTime>),
         ))
         .execute(conn)?;
 
-    Ok(())
+    Ok(updated > 0)
 }
 
-pub fn get_ready_maintenance_jobs(
-    conn: &mut SqliteConnection,
-) -> Result<Option<Vec<(models::Maintenance, models::Job)>>, dieselError> {
-    use crate::schema::jobs::dsl::*;
+/// ClaimCandidate is a due maintenance together with what decides whether it
+/// can be claimed.
+#[derive(Queryable)]
+struct ClaimCandidate {
+    id: Option<i32>,
+    window_end: Option<chrono::NaiveDateTime>,
+    estimated_duration: Option<i32>,
+    window_id: Option<i32>,
+    job_id: i32,
+    max_concurrency: Option<i32>,
+    cluster: Option<String>,
+    job_cluster: Option<String>,
+}
+
+/// How many due maintenances a claim loads at once. Job types and clusters
+/// that reach their concurrency limit are left out of the next batch, so
+/// that a backlog of one of them cannot hide the others.
+const CLAIM_CANDIDATES: i64 = 1000;
+
+/// Candidates are the due maintenances a claim or a dispatch picked: at most
+/// `limit` ready ones that fit into the concurrency limits, by priority, and
+/// the ones whose estimated runtime no longer fits into their downtime window.
+struct Candidates {
+    ready: Vec<ClaimCandidate>,
+    missed: Vec<ClaimCandidate>,
+}
+
+/// pick_candidates selects the maintenances a claim works on. If `uids` is
+/// set only those maintenances are considered. With `redispatch_before` it
+/// selects for a dispatch instead: only maintenances that have not been
+/// dispatched since then are considered, and the ones that have count
+/// against the concurrency limits as if they were running.
+fn pick_candidates(
+    conn: &mut DbConnection,
+    max_attempts: i32,
+    limit: i64,
+    uids: Option<Vec<String>>,
+    limits: &ConcurrencyLimits,
+    redispatch_before: Option<chrono::NaiveDateTime>,
+) -> Result<Candidates, dieselError> {
+    use crate::schema::jobs;
     use crate::schema::maintenances::dsl::*;
-    //AND failed_attempts < $5
 
-    let page_with_book = jobs
+    let mut running = maintenances
+        .inner_join(jobs::table)
+        .select((job_id, cluster, jobs::cluster))
+        .into_boxed();
+    running = match redispatch_before {
+        Some(before) => running.filter(
+            status.eq(models::JobStatus::Running).or(status
+                .eq_any(models::JobStatus::WAITING)
+                .and(dispatched_at.ge(before))),
+        ),
+        None => running.filter(status.eq(models::JobStatus::Running)),
+    };
+    let running = running.load::<(i32, Option<String>, Option<String>)>(conn)?;
+    let mut running_total = running.len() as i64;
+    let mut running_of_job: HashMap<i32, i64> = HashMap::new();
+    let mut running_in_cluster: HashMap<String, i64> = HashMap::new();
+    for (running_job, running_cluster, running_job_cluster) in running {
+        let scope = running_cluster.or(running_job_cluster);
+        *running_of_job.entry(running_job).or_default() += 1;
+        *running_in_cluster
+            .entry(ConcurrencyLimits::scope(scope.as_deref()).to_string())
+            .or_default() += 1;
+    }
+
+    let current_time = chrono::Utc::now().naive_utc();
+    let mut ready = Vec::new();
+    let mut missed = Vec::new();
+    // picked and missed maintenances, the next batch leaves them out
+    let mut seen: Vec<Option<i32>> = Vec::new();
+    let mut full_jobs: HashSet<i32> = HashSet::new();
+    let mut full_clusters: HashSet<String> = HashSet::new();
+    let is_full = |ready: &Vec<ClaimCandidate>, running_total: i64| {
+        ready.len() as i64 >= limit || limits.global.is_some_and(|max| running_total >= max)
+    };
+    loop {
+        let mut due = maintenances
+            .inner_join(jobs::table)
+            .select((
+                id,
+                downtime_window_end,
+                jobs::estimated_duration,
+                window_id,
+                job_id,
+                jobs::max_concurrency,
+                cluster,
+                jobs::cluster,
+            ))
+            .filter(status.eq_any(models::JobStatus::WAITING))
+            .filter(scheduled_for.le(now))
+            .filter(failed_attempts.lt(max_attempts))
+            .filter(
+                downtime_window_start
+                    .is_null()
+                    .or(downtime_window_start.le(now)),
+            )
+            .into_boxed();
+        if let Some(uids) = &uids {
+            due = due.filter(uuid.eq_any(uids.clone()));
+        }
+        if let Some(before) = redispatch_before {
+            due = due.filter(dispatched_at.is_null().or(dispatched_at.lt(before)));
+        }
+        if !seen.is_empty() {
+            due = due.filter(id.ne_all(seen.clone()));
+        }
+        if !full_jobs.is_empty() {
+            due = due.filter(job_id.ne_all(full_jobs.iter().copied().collect::<Vec<_>>()));
+        }
+        if !full_clusters.is_empty() {
+            let scope = diesel::dsl::sql::<diesel::sql_types::Text>(
+                "COALESCE(maintenances.cluster, jobs.cluster, ",
+            )
+            .bind::<diesel::sql_types::Text, _>(ConcurrencyLimits::scope(None).to_string())
+            .sql(")");
+            due = due.filter(scope.ne_all(full_clusters.iter().cloned().collect::<Vec<_>>()));
+        }
+        let batch = due
+            .order((
+                diesel::dsl::sql::<diesel::sql_types::Integer>(db::AGED_PRIORITY).desc(),
+                scheduled_for.asc(),
+                created_at.asc(),
+            ))
+            .limit(CLAIM_CANDIDATES)
+            .load::<ClaimCandidate>(conn)?;
+        let exhausted = (batch.len() as i64) < CLAIM_CANDIDATES;
+
+        for candidate in batch {
+            let fits_window = candidate.window_end.is_none_or(|window_end| {
+                let duration =
+                    chrono::Duration::seconds(candidate.estimated_duration.unwrap_or(0) as i64);
+                current_time + duration < window_end
+            });
+            if !fits_window {
+                seen.push(candidate.id);
+                missed.push(candidate);
+                continue;
+            }
+            if is_full(&ready, running_total) {
+                continue;
+            }
+            let scope = candidate.cluster.clone().or(candidate.job_cluster.clone());
+            let scope = ConcurrencyLimits::scope(scope.as_deref()).to_string();
+            let job_limit = candidate.max_concurrency.map(|max| max as i64);
+            let cluster_limit = limits.clusters.get(&scope).copied();
+            let of_job = running_of_job.entry(candidate.job_id).or_default();
+            let in_cluster = running_in_cluster.entry(scope.clone()).or_default();
+            if job_limit.is_some_and(|max| *of_job >= max) {
+                full_jobs.insert(candidate.job_id);
+                continue;
+            }
+            if cluster_limit.is_some_and(|max| *in_cluster >= max) {
+                full_clusters.insert(scope);
+                continue;
+            }
+            *of_job += 1;
+            *in_cluster += 1;
+            running_total += 1;
+            seen.push(candidate.id);
+            ready.push(candidate);
+        }
+
+        if exhausted || is_full(&ready, running_total) {
+            break;
+        }
+    }
+
+    Ok(Candidates { ready, missed })
+}
+
+/// Claimed are the maintenances a claim has leased to a worker, together with
+/// their job type, and the ones it found to have missed their downtime
+/// window.
+pub struct Claimed {
+    pub ready: Vec<(models::Maintenance, models::Job)>,
+    pub missed: Vec<models::Maintenance>,
+}
+
+/// claim_ready_maintenance_jobs marks at most `limit` ready maintenances as
+/// running, leased to `worker` until `lease_until`, and returns them together
+/// with their job type. If `uids` is set only those maintenances are
+/// considered. It has to run inside `db::write_transaction`, so that a
+/// maintenance is never handed out twice or marked running without being
+/// returned.
+///
+/// A maintenance is only started inside its downtime window. Maintenances
+/// whose estimated runtime no longer fits into the rest of their window are
+/// moved to the next occurrence of their window if they belong to a
+/// recurring one. All others are returned as missed and left for the caller
+/// to end or reschedule.
+///
+/// Maintenances are skipped while their job type, their cluster or the
+/// runner as a whole has as many maintenances running as `limits` allow.
+pub fn claim_ready_maintenance_jobs(
+    conn: &mut DbConnection,
+    max_attempts: i32,
+    limit: i64,
+    uids: Option<Vec<String>>,
+    worker: &str,
+    lease_until: chrono::NaiveDateTime,
+    limits: &ConcurrencyLimits,
+) -> Result<Claimed, dieselError> {
+    use crate::schema::jobs;
+    use crate::schema::maintenances::dsl::*;
+
+    db::lock_claims(conn)?;
+    let candidates = pick_candidates(conn, max_attempts, limit, uids, limits, None)?;
+    let current_time = chrono::Utc::now().naive_utc();
+
+    let mut missed_ids = Vec::new();
+    for ClaimCandidate {
+        id: missed_id,
+        window_end,
+        window_id: window,
+        ..
+    } in candidates.missed
+    {
+        let next = match window {
+            Some(window) => find_window_by_id(conn, window)?.and_then(|window| {
+                let after = window_end.map_or(current_time, |end| end.max(current_time));
+                schedule::next_window(&window, after).ok()
+            }),
+            None => None,
+        };
+        match next {
+            Some((start, end)) => {
+                update(maintenances)
+                    .filter(id.eq(missed_id))
+                    .filter(status.eq_any(models::JobStatus::sources(
+                        models::JobStatus::WaitingForWindow,
+                        models::JobStatus::can_reschedule_to,
+                    )))
+                    .set((
+                        status.eq(models::JobStatus::WaitingForWindow),
+                        scheduled_for.eq(start),
+                        downtime_window_start.eq(start),
+                        downtime_window_end.eq(end),
+                        updated_at.eq(now),
+                        dispatched_at.eq(None::<chrono::NaiveDateTime>),
+                    ))
+                    .execute(conn)?;
+            }
+            None => missed_ids.push(missed_id),
+        }
+    }
+
+    let missed = maintenances
+        .filter(id.eq_any(missed_ids))
+        .load::<models::Maintenance>(conn)?;
+
+    let ready_ids: Vec<Option<i32>> = candidates.ready.iter().map(|ready| ready.id).collect();
+    let claimed_ids = update(maintenances)
+        .filter(id.eq_any(ready_ids))
+        .filter(status.eq_any(models::JobStatus::sources(
+            models::JobStatus::Running,
+            models::JobStatus::can_transition_to,
+        )))
+        .set((
+            status.eq(models::JobStatus::Running),
+            updated_at.eq(now),
+            worker_id.eq(worker),
+            lease_expires_at.eq(lease_until),
+            dispatched_at.eq(None::<chrono::NaiveDateTime>),
+        ))
+        .returning(id)
+        .get_results::<Option<i32>>(conn)?;
+
+    let claimed = jobs::table
         .inner_join(maintenances)
-        .filter(status.eq(models::JobStatus::Queued.to_string()))
-        .filter(scheduled_for.le(now))
+        .filter(id.eq_any(claimed_ids))
         .select((models::Maintenance::as_select(), models::Job::as_select()))
-        .get_results::<(models::Maintenance, models::Job)>(conn)
-        .optional()?;
+        .get_results::<(models::Maintenance, models::Job)>(conn)?;
+
+    let runs: Vec<models::NewMaintenanceRun> = claimed
+        .iter()
+        .map(|(maintenance, _)| models::NewMaintenanceRun {
+            maintenance_uuid: maintenance.uuid.clone(),
+            attempt: maintenance.failed_attempts + 1,
+            status: models::JobStatus::Running,
+            scheduled_for: maintenance.scheduled_for,
+            worker_id: Some(worker.to_string()),
+            started_at: Some(current_time),
+        })
+        .collect();
+    insert_into(crate::schema::maintenance_runs::table)
+        .values(&runs)
+        .execute(conn)?;
+
+    Ok(Claimed {
+        ready: claimed,
+        missed,
+    })
+}
+
+/// dispatch_ready_maintenances marks at most `limit` maintenances that a
+/// claim would pick as dispatched and returns their uuids, so that a message
+/// can be published for each of them. Maintenances that have missed their
+/// downtime window are dispatched as well, for the claim to deal with them.
+/// Maintenances dispatched before `redispatch_before` whose message never
+/// led to a claim are dispatched again. It has to run inside
+/// `db::write_transaction`.
+pub fn dispatch_ready_maintenances(
+    conn: &mut DbConnection,
+    max_attempts: i32,
+    limit: i64,
+    limits: &ConcurrencyLimits,
+    redispatch_before: chrono::NaiveDateTime,
+) -> Result<Vec<String>, dieselError> {
+    use crate::schema::maintenances::dsl::*;
+
+    db::lock_claims(conn)?;
+    let candidates = pick_candidates(
+        conn,
+        max_attempts,
+        limit,
+        None,
+        limits,
+        Some(redispatch_before),
+    )?;
+    let ids: Vec<Option<i32>> = candidates
+        .ready
+        .iter()
+        .chain(&candidates.missed)
+        .map(|candidate| candidate.id)
+        .collect();
+
+    update(maintenances)
+        .filter(id.eq_any(ids))
+        .filter(status.eq_any(models::JobStatus::WAITING))
+        .set(dispatched_at.eq(chrono::Utc::now().naive_utc()))
+        .returning(uuid)
+        .get_results::<String>(conn)
+}
 
+/// undispatch_maintenance forgets that a waiting maintenance whose message
+/// has been dropped was dispatched, so that it is dispatched again once it
+/// can be claimed.
+pub fn undispatch_maintenance(conn: &mut DbConnection, uid: String) -> Result<(), dieselError> {
+    use crate::schema::maintenances::dsl::*;
     update(maintenances)
-        .filter(status.eq(models::JobStatus::Queued.to_string()))
-        .filter(scheduled_for.le(now))
+        .filter(uuid.eq(uid))
+        .filter(status.eq_any(models::JobStatus::WAITING))
+        .set(dispatched_at.eq(None::<chrono::NaiveDateTime>))
+        .execute(conn)?;
+
+    Ok(())
+}
+
+/// renew_lease extends the lease `worker` holds on a running maintenance.
+/// It returns false if the maintenance is no longer leased to `worker`.
+pub fn renew_lease(
+    conn: &mut DbConnection,
+    uid: String,
+    worker: &str,
+    lease_until: chrono::NaiveDateTime,
+) -> Result<bool, dieselError> {
+    use crate::schema::maintenances::dsl::*;
+    let renewed = update(maintenances)
+        .filter(uuid.eq(uid))
+        .filter(status.eq(models::JobStatus::Running))
+        .filter(worker_id.eq(worker))
+        .set(lease_expires_at.eq(lease_until))
+        .execute(conn)?;
+
+    Ok(renewed > 0)
+}
+
+/// adopt_maintenance leases a running maintenance to `worker`, provided its
+/// lease has expired or already belongs to `worker`, and returns it together
+/// with its job type. It returns None if another worker still holds the
+/// lease or the maintenance is not running.
+pub fn adopt_maintenance(
+    conn: &mut DbConnection,
+    uid: String,
+    worker: &str,
+    lease_until: chrono::NaiveDateTime,
+) -> Result<Option<(models::Maintenance, models::Job)>, dieselError> {
+    use crate::schema::jobs;
+    use crate::schema::maintenances::dsl::*;
+
+    db::write_transaction(conn, |conn| {
+        let adopted = update(maintenances)
+            .filter(uuid.eq(uid.clone()))
+            .filter(status.eq(models::JobStatus::Running))
+            .filter(
+                lease_expires_at
+                    .is_null()
+                    .or(lease_expires_at.lt(now))
+                    .or(worker_id.eq(worker)),
+            )
+            .set((worker_id.eq(worker), lease_expires_at.eq(lease_until)))
+            .execute(conn)?;
+        if adopted == 0 {
+            return Ok(None);
+        }
+
+        jobs::table
+            .inner_join(maintenances)
+            .filter(uuid.eq(uid))
+            .select((models::Maintenance::as_select(), models::Job::as_select()))
+            .first::<(models::Maintenance, models::Job)>(conn)
+            .optional()
+    })
+}
+
+/// requeue_expired_leases puts running maintenances whose lease has expired
+/// back into the queue, counting the lost run as a failed attempt. It returns
+/// the number of requeued maintenances and the ones that have run out of
+/// attempts this way, which are left running for the caller to end or
+/// reschedule. It has to run inside `db::write_transaction`.
+pub fn requeue_expired_leases(
+    conn: &mut DbConnection,
+    max_attempts: i32,
+) -> Result<(usize, Vec<models::Maintenance>), dieselError> {
+    use crate::schema::maintenance_runs;
+    use crate::schema::maintenances::dsl::*;
+
+    let expired = status
+        .eq(models::JobStatus::Running)
+        .and(lease_expires_at.is_null().or(lease_expires_at.lt(now)));
+
+    update(maintenance_runs::table)
+        .filter(maintenance_runs::finished_at.is_null())
+        .filter(
+            maintenance_runs::maintenance_uuid.eq_any(maintenances.filter(expired).select(uuid)),
+        )
         .set((
-            status.eq(models::JobStatus::Running.to_string()),
+            maintenance_runs::status.eq(models::JobStatus::Failed),
+            maintenance_runs::finished_at.eq(now),
+            maintenance_runs::failure_reason.eq("lease expired"),
+        ))
+        .execute(conn)?;
+
+    let requeued = update(maintenances)
+        .filter(expired)
+        .filter(failed_attempts.lt(max_attempts - 1))
+        .filter(status.eq_any(models::JobStatus::sources(
+            models::JobStatus::Queued,
+            models::JobStatus::can_retry_to,
+        )))
+        .set((
+            status.eq(models::JobStatus::Queued),
             updated_at.eq(now),
+            failed_attempts.eq(failed_attempts + 1),
+            worker_id.eq(None::<String>),
+            lease_expires_at.eq(None::<chrono::NaiveDateTime>),
+            dispatched_at.eq(None::<chrono::NaiveDateTime>),
         ))
         .execute(conn)?;
 
-    Ok(page_with_book)
+    let exhausted = maintenances
+        .filter(expired)
+        .load::<models::Maintenance>(conn)?;
+
+    Ok((requeued, exhausted))
+}
+
+/// get_targets returns the distinct cluster and namespace pairs Kubernetes
+/// Jobs are created in: the ones of all job types and the ones running
+/// maintenances override them with.
+pub fn get_targets(conn: &mut DbConnection) -> Result<Vec<Target>, dieselError> {
+    use crate::schema::jobs;
+    use crate::schema::maintenances::dsl::*;
+
+    let mut pairs = jobs::table
+        .select((jobs::cluster, jobs::namespace))
+        .distinct()
+        .load::<(Option<String>, Option<String>)>(conn)?;
+    let overrides = maintenances
+        .inner_join(jobs::table)
+        .filter(status.eq(models::JobStatus::Running))
+        .filter(cluster.is_not_null().or(namespace.is_not_null()))
+        .select((cluster, namespace, jobs::cluster, jobs::namespace))
+        .load::<(
+            Option<String>,
+            Option<String>,
+            Option<String>,
+            Option<String>,
+        )>(conn)?;
+    pairs.extend(
+        overrides
+            .into_iter()
+            .map(|(c, n, job_c, job_n)| (c.or(job_c), n.or(job_n))),
+    );
+    let mut targets: Vec<Target> = Vec::new();
+    for (cluster_name, namespace_name) in pairs {
+        let target = Target {
+            cluster: cluster_name,
+            namespace: namespace_name,
+        };
+        if !targets.contains(&target) {
+            targets.push(target);
+        }
+    }
+
+    Ok(targets)
+}
+
+/// flag_window_overrun marks a maintenance that was still running when its
+/// downtime window closed.
+pub fn flag_window_overrun(conn: &mut DbConnection, uid: String) -> Result<(), dieselError> {
+    use crate::schema::maintenances::dsl::*;
+    update(maintenances)
+        .filter(uuid.eq(uid))
+        .set((window_overrun.eq(true), updated_at.eq(now)))
+        .execute(conn)?;
+
+    Ok(())
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use super::*;
+    use crate::db::testing::{due, maintenance, TestDb};
+    use crate::models::JobStatus;
+    use serde_json::json;
+
+    const WORKER: &str = "worker-1";
+
+    /// insert queues a maintenance `uuid` of job type `job_id`, due a minute
+    /// ago unless `fields` say otherwise.
+    fn insert(conn: &mut DbConnection, uuid: &str, job_id: i32, fields: serde_json::Value) {
+        let mut queued = maintenance(uuid, job_id, fields);
+        queued.status = JobStatus::Queued;
+        queued.scheduled_for.get_or_insert_with(due);
+        queued.created_at = chrono::Utc::now().naive_utc();
+        assert!(insert_new_maintenance(conn, queued).unwrap());
+    }
+
+    /// claim claims at most `limit` maintenances for `WORKER` and returns
+    /// their uuids, sorted.
+    fn claim(conn: &mut DbConnection, limit: i64, limits: &ConcurrencyLimits) -> Vec<String> {
+        let lease_until = chrono::Utc::now().naive_utc() + chrono::Duration::minutes(1);
+        let claimed = db::write_transaction(conn, |conn| {
+            claim_ready_maintenance_jobs(conn, 5, limit, None, WORKER, lease_until, limits)
+        })
+        .unwrap();
+        let mut uuids: Vec<String> = claimed
+            .ready
+            .into_iter()
+            .map(|(maintenance, _)| maintenance.uuid)
+            .collect();
+        uuids.sort();
+        uuids
+    }
+
+    #[test]
+    fn a_backlog_at_its_limit_does_not_starve_other_job_types() {
+        let db = TestDb::new();
+        let busy = db.insert_job("busy", Some(1));
+        let other = db.insert_job("other", None);
+        let mut conn = db.pool.get().unwrap();
+        conn.transaction::<_, dieselError, _>(|conn| {
+            for n in 0..CLAIM_CANDIDATES + 10 {
+                insert(
+                    conn,
+                    &format!("busy-{}", n),
+                    busy.id,
+                    json!({ "priority": 10 }),
+                );
+            }
+            Ok(())
+        })
+        .unwrap();
+        insert(&mut conn, "other", other.id, json!({}));
+
+        let claimed = claim(&mut conn, 5, &ConcurrencyLimits::default());
+        assert_eq!(claimed, vec!["busy-0".to_string(), "other".to_string()]);
+        // with its only slot taken the busy job type is skipped right away
+        insert(&mut conn, "other-2", other.id, json!({}));
+        assert_eq!(
+            claim(&mut conn, 5, &ConcurrencyLimits::default()),
+            vec!["other-2"]
+        );
+    }
+
+    #[test]
+    fn a_cluster_at_its_limit_does_not_starve_other_clusters() {
+        let db = TestDb::new();
+        let job = db.insert_job("drain", None);
+        let mut conn = db.pool.get().unwrap();
+        conn.transaction::<_, dieselError, _>(|conn| {
+            for n in 0..CLAIM_CANDIDATES + 10 {
+                let fields = json!({ "priority": 10, "cluster": "eu-1" });
+                insert(conn, &format!("eu-{}", n), job.id, fields);
+            }
+            Ok(())
+        })
+        .unwrap();
+        insert(&mut conn, "default", job.id, json!({}));
+        let limits = ConcurrencyLimits {
+            global: None,
+            clusters: HashMap::from([("eu-1".to_string(), 2)]),
+        };
+
+        let claimed = claim(&mut conn, 5, &limits);
+        assert_eq!(claimed, vec!["default", "eu-0", "eu-1"]);
+    }
+
+    #[test]
+    fn a_claim_takes_at_most_limit_maintenances() {
+        let db = TestDb::new();
+        let job = db.insert_job("drain", None);
+        let mut conn = db.pool.get().unwrap();
+        for n in 0..5 {
+            insert(&mut conn, &format!("m-{}", n), job.id, json!({}));
+        }
+
+        let limits = ConcurrencyLimits::default();
+        let batches: Vec<Vec<String>> = (0..4).map(|_| claim(&mut conn, 2, &limits)).collect();
+        let sizes: Vec<usize> = batches.iter().map(Vec::len).collect();
+        assert_eq!(sizes, vec![2, 2, 1, 0]);
+        let mut claimed: Vec<String> = batches.concat();
+        claimed.sort();
+        claimed.dedup();
+        assert_eq!(claimed.len(), 5);
+    }
+
+    #[test]
+    fn concurrent_claims_never_hand_out_a_maintenance_twice() {
+        let db = TestDb::new();
+        let job = db.insert_job("drain", None);
+        let mut conn = db.pool.get().unwrap();
+        conn.transaction::<_, dieselError, _>(|conn| {
+            for n in 0..40 {
+                insert(conn, &format!("m-{}", n), job.id, json!({}));
+            }
+            Ok(())
+        })
+        .unwrap();
+        drop(conn);
+
+        let claimers: Vec<_> = (0..4)
+            .map(|_| {
+                let pool = db.pool.clone();
+                std::thread::spawn(move || {
+                    let mut conn = pool.get().unwrap();
+                    let mut claimed = Vec::new();
+                    loop {
+                        let batch = claim(&mut conn, 3, &ConcurrencyLimits::default());
+                        assert!(batch.len() <= 3);
+                        if batch.is_empty() {
+                            return claimed;
+                        }
+                        claimed.extend(batch);
+                    }
+                })
+            })
+            .collect();
+        let mut claimed: Vec<String> = claimers
+            .into_iter()
+            .flat_map(|claimer| claimer.join().unwrap())
+            .collect();
+        claimed.sort();
+        let handed_out = claimed.len();
+        claimed.dedup();
+        assert_eq!(handed_out, 40);
+        assert_eq!(claimed.len(), 40);
+    }
+
+    #[test]
+    fn only_the_worker_holding_a_lease_renews_it() {
+        let db = TestDb::new();
+        let job = db.insert_job("drain", None);
+        let mut conn = db.pool.get().unwrap();
+        insert(&mut conn, "a", job.id, json!({}));
+        assert_eq!(
+            claim(&mut conn, 1, &ConcurrencyLimits::default()),
+            vec!["a"]
+        );
+
+        let later = db.find("a").lease_expires_at.unwrap() + chrono::Duration::minutes(5);
+        assert!(renew_lease(&mut conn, "a".to_string(), WORKER, later).unwrap());
+        assert_eq!(db.find("a").lease_expires_at, Some(later));
+        let even_later = later + chrono::Duration::minutes(5);
+        assert!(!renew_lease(&mut conn, "a".to_string(), "worker-2", even_later).unwrap());
+        assert_eq!(db.find("a").lease_expires_at, Some(later));
+        assert!(!renew_lease(&mut conn, "b".to_string(), WORKER, even_later).unwrap());
+    }
+
+    #[test]
+    fn a_maintenance_is_adopted_once_its_lease_expired() {
+        let db = TestDb::new();
+        let job = db.insert_job("drain", None);
+        let mut conn = db.pool.get().unwrap();
+        insert(&mut conn, "a", job.id, json!({}));
+        assert_eq!(
+            claim(&mut conn, 1, &ConcurrencyLimits::default()),
+            vec!["a"]
+        );
+        let lease_until = chrono::Utc::now().naive_utc() + chrono::Duration::minutes(1);
+
+        let taken = adopt_maintenance(&mut conn, "a".to_string(), "worker-2", lease_until);
+        assert!(taken.unwrap().is_none());
+        // the own lease can always be picked up again, e.g. after a restart
+        let own = adopt_maintenance(&mut conn, "a".to_string(), WORKER, lease_until);
+        assert!(own.unwrap().is_some());
+
+        db.expire_lease("a");
+        let (adopted, job_type) =
+            adopt_maintenance(&mut conn, "a".to_string(), "worker-2", lease_until)
+                .unwrap()
+                .unwrap();
+        assert_eq!(adopted.worker_id.as_deref(), Some("worker-2"));
+        assert_eq!(adopted.lease_expires_at, Some(lease_until));
+        assert_eq!(job_type.id, job.id);
+        assert!(!renew_lease(&mut conn, "a".to_string(), WORKER, lease_until).unwrap());
+    }
+
+    #[test]
+    fn expired_leases_are_requeued_as_failed_attempts() {
+        let db = TestDb::new();
+        let job = db.insert_job("drain", None);
+        let mut conn = db.pool.get().unwrap();
+        insert(&mut conn, "a", job.id, json!({}));
+        insert(&mut conn, "b", job.id, json!({}));
+        assert_eq!(
+            claim(&mut conn, 2, &ConcurrencyLimits::default()),
+            vec!["a", "b"]
+        );
+        db.expire_lease("a");
+
+        let (requeued, exhausted) =
+            db::write_transaction(&mut conn, |conn| requeue_expired_leases(conn, 5)).unwrap();
+        assert_eq!(requeued, 1);
+        assert!(exhausted.is_empty());
+
+        let lost = db.find("a");
+        assert_eq!(lost.status, JobStatus::Queued);
+        assert_eq!(lost.failed_attempts, 1);
+        assert_eq!(lost.worker_id, None);
+        assert_eq!(lost.lease_expires_at, None);
+        let runs = get_runs_of_maintenance(&mut conn, "a".to_string()).unwrap();
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].status, JobStatus::Failed);
+        assert_eq!(runs[0].failure_reason.as_deref(), Some("lease expired"));
+        assert!(runs[0].finished_at.is_some());
+        assert_eq!(db.find("b").status, JobStatus::Running);
+        let runs = get_runs_of_maintenance(&mut conn, "b".to_string()).unwrap();
+        assert_eq!(runs[0].finished_at, None);
+    }
+
+    #[test]
+    fn expired_leases_without_attempts_left_are_handed_back() {
+        use crate::schema::maintenances::dsl;
+        let db = TestDb::new();
+        let job = db.insert_job("drain", None);
+        let mut conn = db.pool.get().unwrap();
+        insert(&mut conn, "a", job.id, json!({}));
+        update(dsl::maintenances.filter(dsl::uuid.eq("a")))
+            .set(dsl::failed_attempts.eq(4))
+            .execute(&mut conn)
+            .unwrap();
+        assert_eq!(
+            claim(&mut conn, 1, &ConcurrencyLimits::default()),
+            vec!["a"]
+        );
+        db.expire_lease("a");
+
+        let (requeued, exhausted) =
+            db::write_transaction(&mut conn, |conn| requeue_expired_leases(conn, 5)).unwrap();
+        assert_eq!(requeued, 0);
+        let uuids: Vec<&str> = exhausted.iter().map(|m| m.uuid.as_str()).collect();
+        assert_eq!(uuids, vec!["a"]);
+        // ending it is up to the caller
+        assert_eq!(db.find("a").status, JobStatus::Running);
+        let runs = get_runs_of_maintenance(&mut conn, "a".to_string()).unwrap();
+        assert_eq!(runs[0].status, JobStatus::Failed);
+    }
+
+    /// claim_one_by_one claims the due maintenances one at a time and
+    /// returns their uuids in the order they were claimed.
+    fn claim_one_by_one(conn: &mut DbConnection) -> Vec<String> {
+        std::iter::from_fn(|| claim(conn, 1, &ConcurrencyLimits::default()).pop()).collect()
+    }
+
+    #[test]
+    fn claims_go_by_priority_then_due_date_then_submission() {
+        use crate::schema::maintenances::dsl;
+        let db = TestDb::new();
+        let job = db.insert_job("drain", None);
+        let mut conn = db.pool.get().unwrap();
+        let current_time = chrono::Utc::now().naive_utc();
+        let earlier = current_time - chrono::Duration::minutes(2);
+        let later = current_time - chrono::Duration::minutes(1);
+        insert(
+            &mut conn,
+            "low",
+            job.id,
+            json!({ "scheduled_for": earlier }),
+        );
+        insert(
+            &mut conn,
+            "high",
+            job.id,
+            json!({ "priority": 5, "scheduled_for": later }),
+        );
+        for (uuid, scheduled_for) in [
+            ("mid-later", later),
+            ("mid-submitted-last", later),
+            ("mid-earlier", earlier),
+        ] {
+            let fields = json!({ "priority": 1, "scheduled_for": scheduled_for });
+            insert(&mut conn, uuid, job.id, fields);
+        }
+        update(dsl::maintenances.filter(dsl::uuid.eq("mid-submitted-last")))
+            .set(dsl::created_at.eq(current_time + chrono::Duration::seconds(1)))
+            .execute(&mut conn)
+            .unwrap();
+
+        assert_eq!(
+            claim_one_by_one(&mut conn),
+            vec![
+                "high",
+                "mid-earlier",
+                "mid-later",
+                "mid-submitted-last",
+                "low"
+            ]
+        );
+    }
+
+    #[test]
+    fn waiting_raises_the_priority_by_one_every_ten_minutes() {
+        let db = TestDb::new();
+        let job = db.insert_job("drain", None);
+        let mut conn = db.pool.get().unwrap();
+        let current_time = chrono::Utc::now().naive_utc();
+        let due_since =
+            |minutes| json!({ "scheduled_for": current_time - chrono::Duration::minutes(minutes) });
+        // aged to priority 4 and 6
+        insert(&mut conn, "low-45-min", job.id, due_since(45));
+        insert(&mut conn, "low-65-min", job.id, due_since(65));
+        let mut high = due_since(1);
+        high["priority"] = json!(5);
+        insert(&mut conn, "high", job.id, high);
+
+        assert_eq!(
+            claim_one_by_one(&mut conn),
+            vec!["low-65-min", "high", "low-45-min"]
+        );
+    }
 }
@@ -0,0 +1,163 @@
+//! Kubernetes clusters the runner creates Jobs in. Every job type, and every
+//! maintenance on its own, can name a cluster and a namespace; without them
+//! the Jobs run in the cluster and namespace the runner is configured for.
+//!
+//! A cluster name is looked up in `CLUSTER_CONFIG_DIR` first, a directory of
+//! kubeconfig files named after their cluster, e.g. mounted from one Secret
+//! per cluster. Names that have no file there are taken to be contexts of
+//! the runner's own kubeconfig.
+use crate::error::Error;
+use crate::models::{Job, Maintenance};
+use kube::config::{Config, KubeConfigOptions, Kubeconfig};
+use kube::{Api, Client, Resource};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// Longest namespace name Kubernetes accepts.
+const MAX_NAMESPACE_LENGTH: usize = 63;
+/// Longest cluster name that is accepted.
+const MAX_CLUSTER_LENGTH: usize = 253;
+
+/// Target is the cluster and namespace a Kubernetes Job is created in. None
+/// stands for the default of the runner.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Target {
+    pub cluster: Option<String>,
+    pub namespace: Option<String>,
+}
+
+impl Target {
+    /// of returns the target of `job`. Cluster and namespace of the
+    /// maintenance take precedence over the ones of its job type.
+    pub fn of(job: &Maintenance, job_type: &Job) -> Target {
+        Target {
+            cluster: job.cluster.clone().or(job_type.cluster.clone()),
+            namespace: job.namespace.clone().or(job_type.namespace.clone()),
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}/{}",
+            self.cluster.as_deref().unwrap_or("default"),
+            self.namespace.as_deref().unwrap_or("default")
+        )
+    }
+}
+
+/// validate checks that `cluster` and `namespace` are valid names. A
+/// namespace has to be an RFC 1123 label. A cluster name may contain
+/// letters, digits, '-', '_' and '.', but may not start with a '.', so that
+/// it can not escape `CLUSTER_CONFIG_DIR`.
+pub fn validate(cluster: Option<&str>, namespace: Option<&str>) -> Result<(), String> {
+    if let Some(cluster) = cluster {
+        let valid = !cluster.is_empty()
+            && cluster.len() <= MAX_CLUSTER_LENGTH
+            && !cluster.starts_with('.')
+            && cluster
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+        if !valid {
+            return Err(format!("invalid cluster name {}", cluster));
+        }
+    }
+    if let Some(namespace) = namespace {
+        let valid = !namespace.is_empty()
+            && namespace.len() <= MAX_NAMESPACE_LENGTH
+            && !namespace.starts_with('-')
+            && !namespace.ends_with('-')
+            && namespace
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+        if !valid {
+            return Err(format!(
+                "invalid namespace {}, it has to be a lowercase RFC 1123 label",
+                namespace
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// ClientPool hands out one Kubernetes client per cluster. A client is
+/// built the first time its cluster is asked for and reused afterwards.
+pub struct ClientPool {
+    config_dir: Option<PathBuf>,
+    clients: Mutex<HashMap<Option<String>, Client>>,
+}
+
+impl ClientPool {
+    pub fn new(config_dir: Option<PathBuf>) -> ClientPool {
+        ClientPool {
+            config_dir,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// client returns the client for `cluster`, or for the runner's own
+    /// cluster if None.
+    pub async fn client(&self, cluster: Option<&str>) -> Result<Client, Error> {
+        let key = cluster.map(str::to_string);
+        if let Some(client) = self.clients.lock().unwrap().get(&key) {
+            return Ok(client.clone());
+        }
+
+        let client = Client::try_from(self.config(cluster).await?)?;
+        // a concurrent caller may have built a client in the meantime, keep
+        // whichever came first
+        Ok(self
+            .clients
+            .lock()
+            .unwrap()
+            .entry(key)
+            .or_insert(client)
+            .clone())
+    }
+
+    /// api returns the API for resources of kind `K` in `target`.
+    pub async fn api<K>(&self, target: &Target) -> Result<Api<K>, Error>
+    where
+        K: Resource<Scope = k8s_openapi::NamespaceResourceScope>,
+        <K as Resource>::DynamicType: Default,
+    {
+        let client = self.client(target.cluster.as_deref()).await?;
+        Ok(match &target.namespace {
+            Some(namespace) => Api::namespaced(client, namespace),
+            None => Api::default_namespaced(client),
+        })
+    }
+
+    async fn config(&self, cluster: Option<&str>) -> Result<Config, Error> {
+        let name = cluster.unwrap_or("default");
+        let config_error = |err: &dyn fmt::Display| {
+            Error::Internal(format!("loading config of cluster {}: {}", name, err))
+        };
+        let Some(cluster) = cluster else {
+            return Config::infer().await.map_err(|err| config_error(&err));
+        };
+
+        let path = self.config_dir.as_ref().map(|dir| dir.join(cluster));
+        match path {
+            Some(path) if path.is_file() => {
+                let kubeconfig = Kubeconfig::read_from(&path).map_err(|err| config_error(&err))?;
+                Config::from_custom_kubeconfig(kubeconfig, &KubeConfigOptions::default())
+                    .await
+                    .map_err(|err| config_error(&err))
+            }
+            _ => {
+                let options = KubeConfigOptions {
+                    context: Some(cluster.to_string()),
+                    ..Default::default()
+                };
+                Config::from_kubeconfig(&options)
+                    .await
+                    .map_err(|err| config_error(&err))
+            }
+        }
+    }
+}
@@ -1,52 +1,264 @@
 use crate::actions;
-use crate::models::{Job, JobStatus, Maintenance};
-use crate::queue::Queue;
+use crate::cluster::Target;
+use crate::db::{self, DbConnection};
+use crate::models::{Job, JobStatus, Maintenance, RunReport};
+use crate::queue::{ConcurrencyLimits, Queue};
+use crate::schedule;
 use crate::DbPool;
+use diesel::Connection;
+
+/// Delay before the first retry of a failed maintenance.
+const RETRY_BASE_DELAY_SECS: i64 = 30;
+/// Upper bound for the delay between two retries.
+const RETRY_MAX_DELAY_SECS: i64 = 60 * 60;
+/// How long a pulled maintenance stays leased to a worker without renewal.
+const LEASE_DURATION_SECS: i64 = 60;
+/// How long a dispatched maintenance waits to be claimed before it is
+/// dispatched again, in case its message got lost.
+const REDISPATCH_AFTER_SECS: i64 = 10 * 60;
 
 #[derive(Debug, Clone)]
 pub struct DatabaseQueue {
     db: DbPool,
     max_attempts: u32,
+    worker_id: String,
+    limits: ConcurrencyLimits,
 }
 
 impl DatabaseQueue {
-    pub fn new(db: DbPool) -> DatabaseQueue {
-        let queue = DatabaseQueue {
+    pub fn new(db: DbPool, limits: ConcurrencyLimits) -> DatabaseQueue {
+        DatabaseQueue {
             db,
             max_attempts: 5,
+            worker_id: ulid::Ulid::new().to_string(),
+            limits,
+        }
+    }
+
+    /// claim leases the given maintenances to this worker, as far as they
+    /// are ready to run.
+    pub fn claim(
+        &self,
+        job_ids: Vec<String>,
+    ) -> Result<Vec<(Maintenance, Job)>, crate::error::Error> {
+        self.claim_ready(job_ids.len() as i64, Some(job_ids))
+    }
+
+    /// claim_ready leases at most `limit` ready maintenances to this worker.
+    /// Maintenances that have missed their downtime window end as missed,
+    /// or wait for their next occurrence if they are recurring.
+    fn claim_ready(
+        &self,
+        limit: i64,
+        job_ids: Option<Vec<String>>,
+    ) -> Result<Vec<(Maintenance, Job)>, crate::error::Error> {
+        let mut conn = self.db.get().unwrap();
+        db::write_transaction(&mut conn, |conn| {
+            let claimed = actions::claim_ready_maintenance_jobs(
+                conn,
+                self.max_attempts as i32,
+                limit,
+                job_ids,
+                &self.worker_id,
+                self.lease_until(),
+                &self.limits,
+            )?;
+            for maintenance in claimed.missed {
+                self.complete(conn, maintenance, JobStatus::WindowMissed)?;
+            }
+            Ok(claimed.ready)
+        })
+    }
+
+    /// dispatch marks at most `limit` maintenances that are ready to be
+    /// claimed as dispatched and returns their uuids. A maintenance is
+    /// dispatched again once it has been claimed and went back to waiting,
+    /// after `undispatch`, or if it has not been claimed within
+    /// `REDISPATCH_AFTER_SECS`.
+    pub fn dispatch(&self, limit: u32) -> Result<Vec<String>, crate::error::Error> {
+        let mut conn = self.db.get().unwrap();
+        let job_ids = db::write_transaction(&mut conn, |conn| {
+            actions::dispatch_ready_maintenances(
+                conn,
+                self.max_attempts as i32,
+                limit as i64,
+                &self.limits,
+                chrono::Utc::now().naive_utc() - chrono::Duration::seconds(REDISPATCH_AFTER_SECS),
+            )
+        })?;
+        Ok(job_ids)
+    }
+
+    /// undispatch makes a waiting maintenance whose message is gone ready to
+    /// be dispatched again.
+    pub fn undispatch(&self, job_id: String) -> Result<(), crate::error::Error> {
+        let mut conn = self.db.get().unwrap();
+        actions::undispatch_maintenance(&mut conn, job_id)?;
+        Ok(())
+    }
+
+    /// complete ends the current occurrence of a maintenance with `status`,
+    /// after its last attempt or when it missed its downtime window.
+    /// Recurring maintenances wait for their next occurrence again, all
+    /// others keep `status`.
+    fn complete(
+        &self,
+        conn: &mut DbConnection,
+        maintenance: Maintenance,
+        status: JobStatus,
+    ) -> Result<(), crate::error::Error> {
+        let Some(recurrence) = &maintenance.recurrence else {
+            let updated = match status {
+                JobStatus::Failed | JobStatus::TimedOut => {
+                    actions::fail_maintenance(conn, maintenance.uuid, status)?
+                }
+                _ => actions::update_maintenance_status(conn, maintenance.uuid, status)?,
+            };
+            return transitioned(updated, maintenance.status, status);
         };
 
-        queue
+        let next_at = schedule::next_occurrence(recurrence, chrono::Utc::now().naive_utc())
+            .map_err(crate::error::Error::Internal)?;
+        let window = match maintenance.window_id {
+            Some(window_id) => {
+                let window = actions::find_window_by_id(conn, window_id)?.ok_or_else(|| {
+                    crate::error::Error::NotFound(format!("maintenance window {}", window_id))
+                })?;
+                Some(
+                    schedule::next_window(&window, next_at)
+                        .map_err(crate::error::Error::Internal)?,
+                )
+            }
+            None => None,
+        };
+        let next_status = waiting_status(next_at, window.map(|(start, _)| start));
+        maintenance.status.reschedule_to(next_status)?;
+        let updated =
+            actions::reschedule_maintenance(conn, maintenance.uuid, next_at, window, next_status)?;
+        transitioned(updated, maintenance.status, next_status)
+    }
+
+    /// fail_attempt records the running attempt of a maintenance as ended
+    /// with `status` and retries the maintenance, unless it has run out of
+    /// attempts.
+    fn fail_attempt(
+        &self,
+        job_id: String,
+        report: RunReport,
+        status: JobStatus,
+    ) -> Result<(), crate::error::Error> {
+        let mut conn = self.db.get().unwrap();
+        conn.transaction::<_, crate::error::Error, _>(|conn| {
+            let maintenance = actions::find_maintenance_by_os_uuid(conn, job_id.clone())?
+                .ok_or_else(|| crate::error::Error::NotFound(job_id.clone()))?;
+            maintenance.status.transition_to(status)?;
+            actions::finish_run(conn, job_id.clone(), status, report)?;
+            let failed_attempts = maintenance.failed_attempts + 1;
+
+            if failed_attempts < self.max_attempts as i32 {
+                maintenance.status.retry_to(JobStatus::Queued)?;
+                let retry_at = chrono::Utc::now().naive_utc() + self.backoff(failed_attempts);
+                let updated = actions::retry_maintenance(conn, job_id, retry_at)?;
+                transitioned(updated, maintenance.status, JobStatus::Queued)?;
+            } else {
+                self.complete(conn, maintenance, status)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn lease_until(&self) -> chrono::NaiveDateTime {
+        chrono::Utc::now().naive_utc() + chrono::Duration::seconds(LEASE_DURATION_SECS)
+    }
+
+    /// backoff returns the delay before the next attempt of a maintenance
+    /// that has already failed `failed_attempts` times.
+    fn backoff(&self, failed_attempts: i32) -> chrono::Duration {
+        let exponent = (failed_attempts.max(1) - 1).min(16) as u32;
+        let delay = RETRY_BASE_DELAY_SECS.saturating_mul(2_i64.pow(exponent));
+        chrono::Duration::seconds(delay.min(RETRY_MAX_DELAY_SECS))
     }
 }
 
 #[async_trait::async_trait]
 impl Queue for DatabaseQueue {
-    fn push(&self, mut job: Maintenance) -> Result<(), crate::error::Error> {
-        //let scheduled_for = date.unwrap_or(chrono::Utc::now());
-        job.failed_attempts = 0;
-        job.status = JobStatus::Queued.to_string();
+    /// push queues `job`. A maintenance that refers to a recurring window
+    /// gets the next occurrence of that window as its downtime window.
+    /// An ended or waiting maintenance can be submitted again, a running
+    /// one can not.
+    async fn push(&self, mut job: Maintenance) -> Result<(), crate::error::Error> {
         let mut conn = self.db.get().unwrap();
-        actions::insert_new_maintenance(&mut conn, job)?;
-        Ok(())
+        if let Some(window_id) = job.window_id {
+            let window = actions::find_window_by_id(&mut conn, window_id)?.ok_or_else(|| {
+                crate::error::Error::NotFound(format!("maintenance window {}", window_id))
+            })?;
+            let after = job
+                .scheduled_for
+                .unwrap_or_else(|| chrono::Utc::now().naive_utc());
+            let (start, end) =
+                schedule::next_window(&window, after).map_err(crate::error::Error::Internal)?;
+            job.downtime_window_start = Some(start);
+            job.downtime_window_end = Some(end);
+        }
+        // without an explicit date the maintenance is due as soon as its
+        // downtime window opens
+        let scheduled_for = job
+            .scheduled_for
+            .or(job.downtime_window_start)
+            .unwrap_or_else(|| chrono::Utc::now().naive_utc());
+        job.scheduled_for = Some(scheduled_for);
+        job.failed_attempts = 0;
+        job.created_at = chrono::Utc::now().naive_utc();
+        let next_status = waiting_status(scheduled_for, job.downtime_window_start);
+        job.status = next_status;
+
+        conn.transaction::<_, crate::error::Error, _>(|conn| {
+            let job_id = job.uuid.clone();
+            if let Some(existing) = actions::find_maintenance_by_os_uuid(conn, job_id.clone())? {
+                // re-submitting must not restart work in progress
+                existing.status.transition_to(next_status)?;
+                job.created_at = existing.created_at;
+            }
+            if actions::insert_new_maintenance(conn, job)? {
+                return Ok(());
+            }
+            // the maintenance changed since it was read, e.g. a worker
+            // claimed it
+            let current = actions::find_maintenance_by_os_uuid(conn, job_id.clone())?
+                .ok_or_else(|| crate::error::Error::NotFound(job_id.clone()))?;
+            transitioned(false, current.status, next_status)
+        })
     }
 
-    async fn delete_job(&self, job_id: String) -> Result<(), crate::error::Error> {
-        let mut conn = self.db.get().unwrap();
-        actions::delete_maintenance(&mut conn, job_id)?;
-        Ok(())
+    /// fail_job re-queues the maintenance with an exponential backoff until it
+    /// has failed `max_attempts` times, after which it is marked as failed.
+    async fn fail_job(&self, job_id: String, report: RunReport) -> Result<(), crate::error::Error> {
+        self.fail_attempt(job_id, report, JobStatus::Failed)
     }
-    //
-    async fn fail_job(&self, job_id: String) -> Result<(), crate::error::Error> {
-        let mut conn = self.db.get().unwrap();
-        actions::update_maintenance_status(&mut conn, job_id, JobStatus::Failed)?;
-        Ok(())
+
+    /// time_out_job handles a timed out attempt like a failed one, except
+    /// that it is recorded as timed out.
+    async fn time_out_job(
+        &self,
+        job_id: String,
+        report: RunReport,
+    ) -> Result<(), crate::error::Error> {
+        self.fail_attempt(job_id, report, JobStatus::TimedOut)
     }
 
-    async fn finish_job(&self, job_id: String) -> Result<(), crate::error::Error> {
+    async fn finish_job(
+        &self,
+        job_id: String,
+        report: RunReport,
+    ) -> Result<(), crate::error::Error> {
         let mut conn = self.db.get().unwrap();
-        actions::update_maintenance_status(&mut conn, job_id, JobStatus::Finished)?;
-        Ok(())
+        conn.transaction::<_, crate::error::Error, _>(|conn| {
+            let maintenance = actions::find_maintenance_by_os_uuid(conn, job_id.clone())?
+                .ok_or_else(|| crate::error::Error::NotFound(job_id.clone()))?;
+            maintenance.status.transition_to(JobStatus::Succeeded)?;
+            actions::finish_run(conn, job_id.clone(), JobStatus::Succeeded, report)?;
+            self.complete(conn, maintenance, JobStatus::Succeeded)
+        })
     }
 
     async fn pull(
@@ -58,19 +270,369 @@ impl Queue for DatabaseQueue {
         } else {
             number_of_jobs
         };
-        let mut conn = self.db.get().unwrap();
-        let jobs = actions::get_ready_maintenance_jobs(&mut conn)?;
+        self.claim_ready(number_of_jobs as i64, None)
+    }
 
-        if let Some(jobs) = jobs {
-            Ok(jobs)
+    async fn renew_lease(&self, job_id: String) -> Result<(), crate::error::Error> {
+        let mut conn = self.db.get().unwrap();
+        if actions::renew_lease(
+            &mut conn,
+            job_id.clone(),
+            &self.worker_id,
+            self.lease_until(),
+        )? {
+            Ok(())
         } else {
-            Ok(Vec::new())
+            Err(crate::error::Error::NotFound(format!(
+                "lease on {}",
+                job_id
+            )))
         }
     }
 
-    async fn clear(&self) -> Result<(), crate::error::Error> {
+    async fn find_job(&self, job_id: String) -> Result<Option<Maintenance>, crate::error::Error> {
+        let mut conn = self.db.get().unwrap();
+        let maintenance = actions::find_maintenance_by_os_uuid(&mut conn, job_id)?;
+        Ok(maintenance)
+    }
+
+    async fn adopt(
+        &self,
+        job_id: String,
+    ) -> Result<Option<(Maintenance, Job)>, crate::error::Error> {
+        let mut conn = self.db.get().unwrap();
+        let job =
+            actions::adopt_maintenance(&mut conn, job_id, &self.worker_id, self.lease_until())?;
+        Ok(job)
+    }
+
+    /// requeue_expired retries maintenances whose lease has expired. The
+    /// ones that have run out of attempts fail, or wait for their next
+    /// occurrence if they are recurring.
+    async fn requeue_expired(&self) -> Result<usize, crate::error::Error> {
         let mut conn = self.db.get().unwrap();
-        actions::delete_all_maintenance(&mut conn)?;
+        db::write_transaction(&mut conn, |conn| {
+            let (requeued, exhausted) =
+                actions::requeue_expired_leases(conn, self.max_attempts as i32)?;
+            let recovered = requeued + exhausted.len();
+            for maintenance in exhausted {
+                self.complete(conn, maintenance, JobStatus::Failed)?;
+            }
+            Ok(recovered)
+        })
+    }
+
+    /// cancel marks the maintenance as cancelled. A recurring maintenance
+    /// is not rescheduled anymore.
+    async fn cancel(
+        &self,
+        job_id: String,
+    ) -> Result<Option<(Maintenance, Job)>, crate::error::Error> {
+        let mut conn = self.db.get().unwrap();
+        conn.transaction::<_, crate::error::Error, _>(|conn| {
+            let maintenance = actions::find_maintenance_by_os_uuid(conn, job_id.clone())?
+                .ok_or_else(|| crate::error::Error::NotFound(job_id.clone()))?;
+            maintenance.status.transition_to(JobStatus::Cancelled)?;
+            let report = RunReport {
+                failure_reason: Some("cancelled".to_string()),
+                ..Default::default()
+            };
+            actions::finish_run(conn, job_id.clone(), JobStatus::Cancelled, report)?;
+            let updated = actions::update_maintenance_status(conn, job_id, JobStatus::Cancelled)?;
+            transitioned(updated, maintenance.status, JobStatus::Cancelled)?;
+            if maintenance.status != JobStatus::Running {
+                return Ok(None);
+            }
+            let job_type = actions::find_job_by_id(conn, maintenance.job_id)?.ok_or_else(|| {
+                crate::error::Error::NotFound(format!("job {}", maintenance.job_id))
+            })?;
+            Ok(Some((maintenance, job_type)))
+        })
+    }
+
+    async fn flag_window_overrun(&self, job_id: String) -> Result<(), crate::error::Error> {
+        let mut conn = self.db.get().unwrap();
+        actions::flag_window_overrun(&mut conn, job_id)?;
+        Ok(())
+    }
+
+    async fn targets(&self) -> Result<Vec<Target>, crate::error::Error> {
+        let mut conn = self.db.get().unwrap();
+        let mut targets = actions::get_targets(&mut conn)?;
+        if !targets.contains(&Target::default()) {
+            targets.insert(0, Target::default());
+        }
+        Ok(targets)
+    }
+}
+
+/// waiting_status returns the state of a maintenance that is due at
+/// `scheduled_for`, within a downtime window starting at `window_start`.
+fn waiting_status(
+    scheduled_for: chrono::NaiveDateTime,
+    window_start: Option<chrono::NaiveDateTime>,
+) -> JobStatus {
+    let current_time = chrono::Utc::now().naive_utc();
+    if window_start.is_some_and(|start| start > current_time) {
+        JobStatus::WaitingForWindow
+    } else if scheduled_for > current_time {
+        JobStatus::Pending
+    } else {
+        JobStatus::Queued
+    }
+}
+
+/// transitioned turns a status update that found the maintenance in a state
+/// it may not go to `to` from, e.g. because it changed concurrently, into
+/// `Error::InvalidTransition`.
+fn transitioned(updated: bool, from: JobStatus, to: JobStatus) -> Result<(), crate::error::Error> {
+    if updated {
         Ok(())
+    } else {
+        Err(crate::error::Error::InvalidTransition { from, to })
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use super::*;
+    use crate::db::testing::{due, maintenance, TestDb};
+    use serde_json::json;
+
+    fn queue(db: &TestDb) -> DatabaseQueue {
+        DatabaseQueue::new(db.pool.clone(), ConcurrencyLimits::default())
+    }
+
+    #[actix_web::test]
+    async fn resubmitting_clears_left_out_fields() {
+        let db = TestDb::new();
+        let job = db.insert_job("drain", None);
+        let queue = queue(&db);
+        let last_week = chrono::Utc::now().naive_utc() - chrono::Duration::days(7);
+        let first = maintenance(
+            "a",
+            job.id,
+            json!({
+                "scheduled_for": last_week,
+                "downtime_window_start": last_week,
+                "downtime_window_end": last_week + chrono::Duration::hours(1),
+                "recurrence": "0 2 * * *",
+                "parameters": {"force": true},
+                "active_deadline_seconds": 600,
+                "wait_timeout": 900,
+                "cluster": "eu-1",
+                "namespace": "maintenance",
+            }),
+        );
+        queue.push(first).await.unwrap();
+
+        let second = maintenance("a", job.id, json!({ "scheduled_for": due() }));
+        queue.push(second).await.unwrap();
+
+        let resubmitted = db.find("a");
+        assert_eq!(resubmitted.status, JobStatus::Queued);
+        assert_eq!(resubmitted.downtime_window_start, None);
+        assert_eq!(resubmitted.downtime_window_end, None);
+        assert_eq!(resubmitted.recurrence, None);
+        assert_eq!(resubmitted.parameters, None);
+        assert_eq!(resubmitted.active_deadline_seconds, None);
+        assert_eq!(resubmitted.wait_timeout, None);
+        assert_eq!(resubmitted.cluster, None);
+        assert_eq!(resubmitted.namespace, None);
+        // last week's window is gone, so the maintenance runs
+        let claimed = queue.pull(10).await.unwrap();
+        assert_eq!(claimed.len(), 1);
+        assert_eq!(db.find("a").status, JobStatus::Running);
+    }
+
+    #[actix_web::test]
+    async fn resubmitting_a_running_maintenance_fails() {
+        let db = TestDb::new();
+        let job = db.insert_job("drain", None);
+        let queue = queue(&db);
+        let resubmit = || maintenance("a", job.id, json!({ "scheduled_for": due() }));
+        queue.push(resubmit()).await.unwrap();
+        assert_eq!(queue.pull(10).await.unwrap().len(), 1);
+
+        let err = queue.push(resubmit()).await.unwrap_err();
+        assert!(matches!(
+            err,
+            crate::error::Error::InvalidTransition {
+                from: JobStatus::Running,
+                to: JobStatus::Queued,
+            }
+        ));
+        // the upsert checks the status on its own, in case a worker claims
+        // the maintenance after `push` has read it
+        let mut queued = resubmit();
+        queued.status = JobStatus::Queued;
+        let mut conn = db.pool.get().unwrap();
+        assert!(!actions::insert_new_maintenance(&mut conn, queued).unwrap());
+        assert_eq!(db.find("a").status, JobStatus::Running);
+    }
+
+    #[actix_web::test]
+    async fn cancelling_a_running_maintenance_returns_its_job() {
+        let db = TestDb::new();
+        let job = db.insert_job("drain", None);
+        let queue = queue(&db);
+        queue
+            .push(maintenance("a", job.id, json!({ "scheduled_for": due() })))
+            .await
+            .unwrap();
+        let tomorrow = chrono::Utc::now().naive_utc() + chrono::Duration::days(1);
+        queue
+            .push(maintenance(
+                "b",
+                job.id,
+                json!({ "scheduled_for": tomorrow }),
+            ))
+            .await
+            .unwrap();
+        let pulled = queue.pull(10).await.unwrap();
+        assert_eq!(pulled.len(), 1);
+
+        let (cancelled, job_type) = queue.cancel("a".to_string()).await.unwrap().unwrap();
+        assert_eq!(
+            crate::template::job_name(&cancelled),
+            crate::template::job_name(&pulled[0].0)
+        );
+        assert_eq!(job_type.id, job.id);
+        assert_eq!(db.find("a").status, JobStatus::Cancelled);
+        assert!(queue.cancel("b".to_string()).await.unwrap().is_none());
+        assert_eq!(db.find("b").status, JobStatus::Cancelled);
+        assert!(matches!(
+            queue.cancel("a".to_string()).await,
+            Err(crate::error::Error::InvalidTransition { .. })
+        ));
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_an_hour() {
+        let db = TestDb::new();
+        let queue = queue(&db);
+        let delays: Vec<i64> = [1, 2, 3, 4, 7, 8, 100]
+            .into_iter()
+            .map(|failed_attempts| queue.backoff(failed_attempts).num_seconds())
+            .collect();
+        assert_eq!(delays, vec![30, 60, 120, 240, 1920, 3600, 3600]);
+    }
+
+    #[actix_web::test]
+    async fn a_failed_attempt_is_retried_after_the_backoff() {
+        let db = TestDb::new();
+        let job = db.insert_job("drain", None);
+        let queue = queue(&db);
+        queue
+            .push(maintenance("a", job.id, json!({ "scheduled_for": due() })))
+            .await
+            .unwrap();
+        assert_eq!(queue.pull(10).await.unwrap().len(), 1);
+
+        let before = chrono::Utc::now().naive_utc();
+        queue
+            .fail_job("a".to_string(), RunReport::default())
+            .await
+            .unwrap();
+        let after = chrono::Utc::now().naive_utc();
+
+        let retried = db.find("a");
+        assert_eq!(retried.status, JobStatus::Queued);
+        assert_eq!(retried.failed_attempts, 1);
+        assert_eq!(retried.worker_id, None);
+        let retry_at = retried.scheduled_for.unwrap();
+        assert!(retry_at >= before + chrono::Duration::seconds(RETRY_BASE_DELAY_SECS));
+        assert!(retry_at <= after + chrono::Duration::seconds(RETRY_BASE_DELAY_SECS));
+        // not due before the backoff has passed
+        assert!(queue.pull(10).await.unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn the_last_attempt_fails_for_good() {
+        let db = TestDb::new();
+        let job = db.insert_job("drain", None);
+        let queue = queue(&db);
+        queue
+            .push(maintenance("a", job.id, json!({ "scheduled_for": due() })))
+            .await
+            .unwrap();
+
+        for attempt in 1..=queue.max_attempts as i32 {
+            db.make_due("a");
+            assert_eq!(queue.pull(10).await.unwrap().len(), 1);
+            queue
+                .fail_job("a".to_string(), RunReport::default())
+                .await
+                .unwrap();
+            assert_eq!(db.find("a").failed_attempts, attempt);
+        }
+
+        let failed = db.find("a");
+        assert_eq!(failed.status, JobStatus::Failed);
+        db.make_due("a");
+        assert!(queue.pull(10).await.unwrap().is_empty());
+        let mut conn = db.pool.get().unwrap();
+        let runs = actions::get_runs_of_maintenance(&mut conn, "a".to_string()).unwrap();
+        assert_eq!(runs.len(), queue.max_attempts as usize);
+        assert!(runs.iter().all(|run| run.status == JobStatus::Failed));
+        let attempts: Vec<i32> = runs.iter().map(|run| run.attempt).collect();
+        assert_eq!(
+            attempts,
+            (1..=queue.max_attempts as i32).collect::<Vec<_>>()
+        );
+    }
+
+    #[actix_web::test]
+    async fn success_does_not_count_as_a_failed_attempt() {
+        let db = TestDb::new();
+        let job = db.insert_job("drain", None);
+        let queue = queue(&db);
+        queue
+            .push(maintenance("a", job.id, json!({ "scheduled_for": due() })))
+            .await
+            .unwrap();
+        assert_eq!(queue.pull(10).await.unwrap().len(), 1);
+        queue
+            .fail_job("a".to_string(), RunReport::default())
+            .await
+            .unwrap();
+
+        db.make_due("a");
+        assert_eq!(queue.pull(10).await.unwrap().len(), 1);
+        queue
+            .finish_job("a".to_string(), RunReport::default())
+            .await
+            .unwrap();
+
+        let succeeded = db.find("a");
+        assert_eq!(succeeded.status, JobStatus::Succeeded);
+        assert_eq!(succeeded.failed_attempts, 1);
+    }
+
+    #[actix_web::test]
+    async fn an_expired_lease_without_attempts_left_fails_the_maintenance() {
+        let db = TestDb::new();
+        let job = db.insert_job("drain", None);
+        let queue = queue(&db);
+        queue
+            .push(maintenance("a", job.id, json!({ "scheduled_for": due() })))
+            .await
+            .unwrap();
+        for _ in 1..queue.max_attempts {
+            db.make_due("a");
+            assert_eq!(queue.pull(10).await.unwrap().len(), 1);
+            db.expire_lease("a");
+            assert_eq!(queue.requeue_expired().await.unwrap(), 1);
+            assert_eq!(db.find("a").status, JobStatus::Queued);
+        }
+
+        db.make_due("a");
+        assert_eq!(queue.pull(10).await.unwrap().len(), 1);
+        db.expire_lease("a");
+        assert_eq!(queue.requeue_expired().await.unwrap(), 1);
+        let failed = db.find("a");
+        assert_eq!(failed.status, JobStatus::Failed);
+        assert_eq!(failed.failed_attempts, queue.max_attempts as i32);
+        assert_eq!(queue.requeue_expired().await.unwrap(), 0);
     }
 }
@@ -0,0 +1,302 @@
+//! Database backend selection. SQLite is the default for local development,
+//! the `postgres` feature switches the store to a networked PostgreSQL
+//! database that can be shared by several replicas.
+use diesel::migration::{MigrationSource, MigrationVersion};
+use diesel::r2d2::{self, ConnectionManager};
+use diesel::result::Error as dieselError;
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+
+#[cfg(all(feature = "sqlite", feature = "postgres"))]
+compile_error!("the `sqlite` and `postgres` features are mutually exclusive");
+#[cfg(not(any(feature = "sqlite", feature = "postgres")))]
+compile_error!("either the `sqlite` or the `postgres` feature has to be enabled");
+
+#[cfg(feature = "sqlite")]
+pub type DbConnection = diesel::SqliteConnection;
+#[cfg(feature = "postgres")]
+pub type DbConnection = diesel::PgConnection;
+
+pub type DbPool = r2d2::Pool<ConnectionManager<DbConnection>>;
+
+type DbBackend = <DbConnection as diesel::Connection>::Backend;
+
+#[cfg(feature = "sqlite")]
+const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations/sqlite");
+#[cfg(feature = "postgres")]
+const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations/postgres");
+
+/// AGED_PRIORITY is the SQL expression that ready maintenances are claimed
+/// by: their priority plus one for every ten minutes they have been due.
+/// This lets an urgent maintenance jump ahead of routine ones while routine
+/// ones still get their turn eventually.
+#[cfg(feature = "sqlite")]
+pub const AGED_PRIORITY: &str = "maintenances.priority + CAST((julianday('now') \
+    - julianday(maintenances.scheduled_for)) * 86400 AS INTEGER) / 600";
+#[cfg(feature = "postgres")]
+pub const AGED_PRIORITY: &str = "maintenances.priority + CAST(EXTRACT(EPOCH FROM \
+    (LOCALTIMESTAMP - maintenances.scheduled_for)) AS INTEGER) / 600";
+
+pub fn build_pool(database_url: String) -> Result<DbPool, r2d2::PoolError> {
+    let manager = ConnectionManager::<DbConnection>::new(database_url);
+    r2d2::Pool::builder()
+        .connection_customizer(Box::new(SessionSetup))
+        .build(manager)
+}
+
+/// run_migrations applies all pending migrations embedded into the binary and
+/// returns their versions. It refuses to touch a database that has
+/// migrations applied which this binary does not know of, i.e. one that has
+/// already been migrated by a newer release.
+pub fn run_migrations(conn: &mut DbConnection) -> Result<Vec<String>, crate::error::Error> {
+    let migration_error = |err| crate::error::Error::Database(format!("migrations: {}", err));
+
+    let known: Vec<MigrationVersion> = MigrationSource::<DbBackend>::migrations(&MIGRATIONS)
+        .map_err(migration_error)?
+        .iter()
+        .map(|migration| migration.name().version().as_owned())
+        .collect();
+    let unknown: Vec<String> = conn
+        .applied_migrations()
+        .map_err(migration_error)?
+        .iter()
+        .filter(|version| !known.contains(version))
+        .map(|version| version.to_string())
+        .collect();
+    if !unknown.is_empty() {
+        return Err(crate::error::Error::Database(format!(
+            "database schema is newer than this binary, unknown migrations: {}",
+            unknown.join(", ")
+        )));
+    }
+
+    let applied = conn
+        .run_pending_migrations(MIGRATIONS)
+        .map_err(migration_error)?
+        .iter()
+        .map(|version| version.to_string())
+        .collect();
+    Ok(applied)
+}
+
+/// write_transaction runs `f` in a transaction that is going to write. On
+/// SQLite the write lock is taken up front, so that concurrent claims cannot
+/// interleave between their select and update statements.
+#[cfg(feature = "sqlite")]
+pub fn write_transaction<T, E, F>(conn: &mut DbConnection, f: F) -> Result<T, E>
+where
+    F: FnOnce(&mut DbConnection) -> Result<T, E>,
+    E: From<dieselError>,
+{
+    conn.immediate_transaction(f)
+}
+
+/// write_transaction runs `f` in a transaction that is going to write.
+#[cfg(feature = "postgres")]
+pub fn write_transaction<T, E, F>(conn: &mut DbConnection, f: F) -> Result<T, E>
+where
+    F: FnOnce(&mut DbConnection) -> Result<T, E>,
+    E: From<dieselError>,
+{
+    use diesel::Connection;
+    conn.transaction(f)
+}
+
+/// CLAIM_LOCK is the key of the advisory lock that claims take on PostgreSQL.
+#[cfg(feature = "postgres")]
+const CLAIM_LOCK: i64 = 0x6b38_736a_6f62;
+
+/// lock_claims keeps concurrent claims of several replicas from both seeing
+/// a free slot under a concurrency limit. The lock is held until the end of
+/// the transaction. On SQLite `write_transaction` serializes claims already.
+/// On PostgreSQL it is an advisory lock, so that only claims wait for each
+/// other while other writes to `maintenances` go ahead.
+#[cfg(feature = "sqlite")]
+pub fn lock_claims(_conn: &mut DbConnection) -> Result<(), dieselError> {
+    Ok(())
+}
+
+#[cfg(feature = "postgres")]
+pub fn lock_claims(conn: &mut DbConnection) -> Result<(), dieselError> {
+    use diesel::RunQueryDsl;
+    diesel::sql_query("SELECT pg_advisory_xact_lock($1)")
+        .bind::<diesel::sql_types::BigInt, _>(CLAIM_LOCK)
+        .execute(conn)?;
+    Ok(())
+}
+
+/// SessionSetup prepares every new connection of the pool.
+#[derive(Debug)]
+struct SessionSetup;
+
+impl r2d2::CustomizeConnection<DbConnection, r2d2::Error> for SessionSetup {
+    /// Timestamps are stored without time zone and compared against
+    /// `CURRENT_TIMESTAMP`, so the session has to run in UTC on PostgreSQL.
+    #[cfg(feature = "postgres")]
+    fn on_acquire(&self, conn: &mut DbConnection) -> Result<(), r2d2::Error> {
+        use diesel::RunQueryDsl;
+        diesel::sql_query("SET TIME ZONE 'UTC'")
+            .execute(conn)
+            .map_err(r2d2::Error::QueryError)?;
+        Ok(())
+    }
+
+    /// `write_transaction` takes the write lock up front, so a connection
+    /// that finds it taken has to wait for it instead of failing right away
+    /// with `database is locked`.
+    #[cfg(feature = "sqlite")]
+    fn on_acquire(&self, conn: &mut DbConnection) -> Result<(), r2d2::Error> {
+        use diesel::RunQueryDsl;
+        diesel::sql_query(format!(
+            "PRAGMA busy_timeout = {}",
+            BUSY_TIMEOUT.as_millis()
+        ))
+        .execute(conn)
+        .map_err(r2d2::Error::QueryError)?;
+        Ok(())
+    }
+}
+
+/// BUSY_TIMEOUT is how long a SQLite connection waits for a lock held by
+/// another connection.
+#[cfg(feature = "sqlite")]
+const BUSY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Helpers for tests that need a migrated database.
+#[cfg(all(test, feature = "sqlite"))]
+pub mod testing {
+    use super::{build_pool, run_migrations, DbPool};
+    use crate::actions;
+    use crate::models::{Job, Maintenance, NewJob};
+    use std::path::PathBuf;
+
+    /// TestDb is a SQLite database in a directory of its own, which is
+    /// removed on drop.
+    pub struct TestDb {
+        pub pool: DbPool,
+        dir: PathBuf,
+    }
+
+    impl TestDb {
+        pub fn new() -> TestDb {
+            let dir = std::env::temp_dir().join(format!("k8s-job-runner-{}", ulid::Ulid::new()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let pool = build_pool(dir.join("runner.db").to_string_lossy().to_string()).unwrap();
+            run_migrations(&mut pool.get().unwrap()).unwrap();
+            TestDb { pool, dir }
+        }
+
+        /// insert_job adds a job type named `name` that runs at most
+        /// `max_concurrency` maintenances at once.
+        pub fn insert_job(&self, name: &str, max_concurrency: Option<i32>) -> Job {
+            let mut conn = self.pool.get().unwrap();
+            actions::insert_new_job(
+                &mut conn,
+                NewJob {
+                    name: name.to_string(),
+                    version: None,
+                    docker_image: "busybox".to_string(),
+                    docker_image_tag: "latest".to_string(),
+                    template: None,
+                    parameters_schema: None,
+                    estimated_duration: None,
+                    active_deadline_seconds: None,
+                    wait_timeout: None,
+                    cluster: None,
+                    namespace: None,
+                    max_concurrency,
+                },
+            )
+            .unwrap()
+        }
+
+        /// find returns the maintenance `uuid`, which has to exist.
+        pub fn find(&self, uuid: &str) -> Maintenance {
+            let mut conn = self.pool.get().unwrap();
+            actions::find_maintenance_by_os_uuid(&mut conn, uuid.to_string())
+                .unwrap()
+                .unwrap()
+        }
+
+        /// make_due moves the maintenance `uuid` to `due`, e.g. to skip the
+        /// backoff before its next attempt.
+        pub fn make_due(&self, uuid: &str) {
+            use crate::schema::maintenances::dsl;
+            use diesel::prelude::*;
+            let mut conn = self.pool.get().unwrap();
+            diesel::update(dsl::maintenances.filter(dsl::uuid.eq(uuid)))
+                .set(dsl::scheduled_for.eq(due()))
+                .execute(&mut conn)
+                .unwrap();
+        }
+
+        /// expire_lease lets the lease on the maintenance `uuid` run out, as
+        /// if its worker had died.
+        pub fn expire_lease(&self, uuid: &str) {
+            use crate::schema::maintenances::dsl;
+            use diesel::prelude::*;
+            let mut conn = self.pool.get().unwrap();
+            diesel::update(dsl::maintenances.filter(dsl::uuid.eq(uuid)))
+                .set(dsl::lease_expires_at.eq(due()))
+                .execute(&mut conn)
+                .unwrap();
+        }
+    }
+
+    impl Drop for TestDb {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    /// maintenance returns a maintenance `uuid` of job type `job_id`, as it
+    /// is submitted through the API.
+    pub fn maintenance(uuid: &str, job_id: i32, fields: serde_json::Value) -> Maintenance {
+        let mut request = serde_json::json!({ "job_id": job_id });
+        request
+            .as_object_mut()
+            .unwrap()
+            .extend(fields.as_object().unwrap().clone());
+        let mut maintenance: Maintenance = serde_json::from_value(request).unwrap();
+        maintenance.uuid = uuid.to_string();
+        maintenance
+    }
+
+    /// due is a minute ago. The queue compares timestamps with the database
+    /// clock, which SQLite only keeps to the second.
+    pub fn due() -> chrono::NaiveDateTime {
+        chrono::Utc::now().naive_utc() - chrono::Duration::minutes(1)
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use super::testing::TestDb;
+    use super::write_transaction;
+    use diesel::result::Error as dieselError;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn a_write_transaction_waits_for_the_lock() {
+        let db = TestDb::new();
+        let (locked, wait) = mpsc::channel();
+        let holder = {
+            let pool = db.pool.clone();
+            thread::spawn(move || {
+                let mut conn = pool.get().unwrap();
+                write_transaction(&mut conn, |_| {
+                    locked.send(()).unwrap();
+                    thread::sleep(Duration::from_millis(300));
+                    Ok::<_, dieselError>(())
+                })
+            })
+        };
+        wait.recv().unwrap();
+
+        let mut conn = db.pool.get().unwrap();
+        let waited = write_transaction(&mut conn, |_| Ok::<_, dieselError>(()));
+        assert!(waited.is_ok(), "{:?}", waited);
+        assert!(holder.join().unwrap().is_ok());
+    }
+}
@@ -1,7 +1,8 @@
+use crate::models::JobStatus;
+use async_nats::Error as natsError;
 use diesel::result::Error as dieselError;
 use kube::runtime::wait::Error as kubeWaitError;
 use kube::Error as kubeError;
-use thiserror;
 
 #[derive(thiserror::Error, Debug, Clone)]
 pub enum Error {
@@ -11,6 +12,10 @@ pub enum Error {
     Internal(String),
     #[error("Not found: {0}")]
     NotFound(String),
+    #[error("Timed out: {0}")]
+    TimedOut(String),
+    #[error("invalid transition from {from} to {to}")]
+    InvalidTransition { from: JobStatus, to: JobStatus },
 }
 
 impl std::convert::From<dieselError> for Error {
@@ -25,16 +30,18 @@ impl std::convert::From<dieselError> for Error {
 
 impl std::convert::From<kubeError> for Error {
     fn from(err: kubeError) -> Self {
-        match err {
-            _ => Error::Internal(err.to_string()),
-        }
+        Error::Internal(err.to_string())
     }
 }
 
 impl std::convert::From<kubeWaitError> for Error {
     fn from(err: kubeWaitError) -> Self {
-        match err {
-            _ => Error::Internal(err.to_string()),
-        }
+        Error::Internal(err.to_string())
+    }
+}
+
+impl std::convert::From<natsError> for Error {
+    fn from(err: natsError) -> Self {
+        Error::Internal(err.to_string())
     }
 }
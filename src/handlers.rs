@@ -1,27 +1,37 @@
 use super::actions;
 use super::models;
+use crate::cluster;
 use crate::queue::Queue;
+use crate::schedule;
+use crate::template;
+use crate::worker;
 use crate::DbPool;
 use actix_web::{
-    get,
+    delete, get,
     http::{header::ContentType, StatusCode},
-    put, web, Error, HttpResponse, ResponseError, Result,
+    post, put, web, Error, HttpResponse, ResponseError, Result,
 };
-use chrono::{DateTime, Utc};
 use derive_more::{Display, Error};
-use serde::{Deserialize, Serialize};
+use diesel::Connection;
+use kube::api::DeleteParams;
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct OpenstackObject {
-    uuid: Option<String>,
-    planned: Option<DateTime<Utc>>,
-    task: bool,
-}
+/// Bound for the priority of a maintenance in either direction.
+const MAX_PRIORITY: i32 = 1000;
 
 #[derive(Debug, Display, Error)]
 enum UserError {
     #[display(fmt = "An internal error occurred. Please try again later.")]
     InternalError,
+    #[display(fmt = "No objects found")]
+    NotFound,
+    #[display(fmt = "The job is still referenced by maintenances.")]
+    JobInUse,
+    #[display(fmt = "The window is still referenced by maintenances.")]
+    WindowInUse,
+    #[display(fmt = "{}", _0)]
+    InvalidTransition(#[error(not(source))] String),
+    #[display(fmt = "{}", _0)]
+    BadRequest(#[error(not(source))] String),
 }
 
 impl ResponseError for UserError {
@@ -34,49 +44,15 @@ impl ResponseError for UserError {
     fn status_code(&self) -> StatusCode {
         match *self {
             UserError::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
+            UserError::NotFound => StatusCode::NOT_FOUND,
+            UserError::JobInUse | UserError::WindowInUse | UserError::InvalidTransition(_) => {
+                StatusCode::CONFLICT
+            }
+            UserError::BadRequest(_) => StatusCode::BAD_REQUEST,
         }
     }
 }
 
-/*
-#[get("/maintenance/{uuid}")]
-async fn get_data(
-    client: web::Data<jetstream::kv::Store>,
-    pool: web::Data<Pool<ConnectionManager<SqliteConnection>>>,
-    uuid: web::Path<String>,
-) -> Result<HttpResponse, UserError> {
-    let uuid = uuid.into_inner();
-
-    let entry = client
-        .entry(uuid.clone())
-        .await
-        .map_err(|_e| UserError::InternalError)?;
-
-    if let Some(entry) = entry {
-        let result = serde_json::from_slice::<OpenstackObject>(&entry.value)
-            .map_err(|_e| UserError::InternalError)?;
-        return Ok(HttpResponse::Ok().json(result));
-    }
-    Err(UserError::InternalError)
-}
-
-#[put("/maintenance/{uuid}")]
-async fn put_maintenance(
-    client: web::Data<jetstream::kv::Store>,
-    mut object: web::Json<OpenstackObject>,
-    uuid: web::Path<String>,
-) -> Result<HttpResponse, UserError> {
-    let uuid = uuid.into_inner();
-    object.uuid = Some(uuid.clone());
-    let bytes = serde_json::to_vec(&json!(object)).map_err(|_e| UserError::InternalError)?;
-    client
-        .put(uuid.to_string(), bytes.into())
-        .await
-        .map_err(|_e| UserError::InternalError)?;
-
-    return Ok(HttpResponse::Created().json(uuid.to_string()));
-}
-*/
 #[get("/")]
 pub async fn get_all_maintenance(pool: web::Data<DbPool>) -> Result<HttpResponse, Error> {
     // use web::block to offload blocking Diesel code without blocking server thread
@@ -90,7 +66,7 @@ pub async fn get_all_maintenance(pool: web::Data<DbPool>) -> Result<HttpResponse
     if let Some(maint) = maint {
         Ok(HttpResponse::Ok().json(maint))
     } else {
-        let res = HttpResponse::NotFound().body(format!("No objects found"));
+        let res = HttpResponse::NotFound().body("No objects found");
         Ok(res)
     }
 }
@@ -111,29 +87,162 @@ pub async fn get_maintenance(
     if let Some(maint) = maint {
         Ok(HttpResponse::Ok().json(maint))
     } else {
-        let res = HttpResponse::NotFound().body(format!("No objects found"));
+        let res = HttpResponse::NotFound().body("No objects found");
         Ok(res)
     }
 }
 
+/// get_maintenance_runs lists all attempts to run a maintenance.
+#[get("/show/{uuid}/runs")]
+pub async fn get_maintenance_runs(
+    pool: web::Data<DbPool>,
+    os_uuid: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let runs = web::block(move || {
+        let mut conn = pool.get().unwrap();
+        actions::get_runs_of_maintenance(&mut conn, os_uuid.into_inner())
+    })
+    .await?
+    .map_err(|_e| UserError::InternalError)?;
+
+    Ok(HttpResponse::Ok().json(runs))
+}
+
+/// get_maintenance_run_logs serves the pod logs captured for one attempt.
+#[get("/show/{uuid}/runs/{id}/logs")]
+pub async fn get_maintenance_run_logs(
+    pool: web::Data<DbPool>,
+    path: web::Path<(String, i32)>,
+) -> Result<HttpResponse, Error> {
+    let (os_uuid, run_id) = path.into_inner();
+    let logs = web::block(move || {
+        let mut conn = pool.get().unwrap();
+        actions::find_run_of_maintenance(&mut conn, os_uuid, run_id)
+    })
+    .await?
+    .map_err(|_e| UserError::InternalError)?
+    .and_then(|run| run.logs)
+    .ok_or(UserError::NotFound)?;
+
+    Ok(HttpResponse::Ok()
+        .insert_header(ContentType::plaintext())
+        .body(logs))
+}
+
 #[put("/maintenance/{uuid}")]
 pub async fn create_maintenance(
     queue: web::Data<dyn Queue>,
+    pool: web::Data<DbPool>,
     os_uuid: web::Path<String>,
     mut object: web::Json<models::Maintenance>,
 ) -> Result<HttpResponse, Error> {
     object.uuid = os_uuid.into_inner();
     object.id = None;
-    let _result = web::block(move || queue.push(object.0)).await?;
+
+    let job_id = object.job_id;
+    let window_id = object.window_id;
+    let (job_type, window) = web::block(move || {
+        let mut conn = pool.get().unwrap();
+        let job_type = actions::find_job_by_id(&mut conn, job_id)?;
+        let window = match window_id {
+            Some(window_id) => actions::find_window_by_id(&mut conn, window_id)?,
+            None => None,
+        };
+        Ok::<_, diesel::result::Error>((job_type, window))
+    })
+    .await?
+    .map_err(|_e| UserError::InternalError)?;
+    let job_type =
+        job_type.ok_or_else(|| UserError::BadRequest(format!("unknown job {}", job_id)))?;
+    if let (Some(window_id), None) = (window_id, window) {
+        return Err(UserError::BadRequest(format!("unknown window {}", window_id)).into());
+    }
+    job_type
+        .validate_parameters(object.parameters.as_ref())
+        .map_err(UserError::BadRequest)?;
+    validate_timeouts(object.active_deadline_seconds, object.wait_timeout)?;
+    if !(-MAX_PRIORITY..=MAX_PRIORITY).contains(&object.priority) {
+        return Err(UserError::BadRequest(format!(
+            "priority has to be between {} and {}",
+            -MAX_PRIORITY, MAX_PRIORITY
+        ))
+        .into());
+    }
+    cluster::validate(object.cluster.as_deref(), object.namespace.as_deref())
+        .map_err(UserError::BadRequest)?;
+    if let Some(recurrence) = &object.recurrence {
+        schedule::validate_recurrence(recurrence).map_err(UserError::BadRequest)?;
+    }
+
+    queue.push(object.0).await.map_err(|e| match e {
+        err @ crate::error::Error::InvalidTransition { .. } => {
+            UserError::InvalidTransition(err.to_string())
+        }
+        _ => UserError::InternalError,
+    })?;
 
     Ok(HttpResponse::Ok().finish())
 }
 
-#[put("/job/{uuid}")]
+/// cancel_maintenance stops a maintenance that has not ended yet. A queued
+/// maintenance is not run anymore, the Kubernetes Job of a running one is
+/// deleted right away, whether its worker is still alive or not.
+#[post("/maintenance/{uuid}/cancel")]
+pub async fn cancel_maintenance(
+    queue: web::Data<dyn Queue>,
+    clients: web::Data<cluster::ClientPool>,
+    os_uuid: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    match queue.cancel(os_uuid.into_inner()).await {
+        Ok(Some((maintenance, job_type))) => {
+            let target = cluster::Target::of(&maintenance, &job_type);
+            let name = template::job_name(&maintenance);
+            worker::delete_job(&clients, &target, &name, &DeleteParams::foreground()).await;
+            Ok(HttpResponse::Ok().finish())
+        }
+        Ok(None) => Ok(HttpResponse::Ok().finish()),
+        Err(crate::error::Error::NotFound(_)) => Err(UserError::NotFound.into()),
+        Err(err @ crate::error::Error::InvalidTransition { .. }) => {
+            Err(UserError::InvalidTransition(err.to_string()).into())
+        }
+        Err(_e) => Err(UserError::InternalError.into()),
+    }
+}
+
+#[get("/jobs")]
+pub async fn get_all_jobs(pool: web::Data<DbPool>) -> Result<HttpResponse, Error> {
+    let jobs = web::block(move || {
+        let mut conn = pool.get().unwrap();
+        actions::get_all_jobs(&mut conn)
+    })
+    .await?
+    .map_err(|_e| UserError::InternalError)?;
+
+    Ok(HttpResponse::Ok().json(jobs))
+}
+
+#[get("/job/{id}")]
+pub async fn get_job(
+    pool: web::Data<DbPool>,
+    job_id: web::Path<i32>,
+) -> Result<HttpResponse, Error> {
+    let job = web::block(move || {
+        let mut conn = pool.get().unwrap();
+        actions::find_job_by_id(&mut conn, job_id.into_inner())
+    })
+    .await?
+    .map_err(|_e| UserError::InternalError)?
+    .ok_or(UserError::NotFound)?;
+
+    Ok(HttpResponse::Ok().json(job))
+}
+
+#[post("/job")]
 pub async fn create_job(
     pool: web::Data<DbPool>,
-    object: web::Json<models::Job>,
+    object: web::Json<models::NewJob>,
 ) -> Result<HttpResponse, Error> {
+    validate_job(&object)?;
     let result = web::block(move || {
         let mut conn = pool.get().unwrap();
         actions::insert_new_job(&mut conn, object.0)
@@ -141,5 +250,178 @@ pub async fn create_job(
     .await?
     .map_err(|_e| UserError::InternalError)?;
 
-    Ok(HttpResponse::Ok().json(result))
+    Ok(HttpResponse::Created().json(result))
+}
+
+#[put("/job/{id}")]
+pub async fn update_job(
+    pool: web::Data<DbPool>,
+    job_id: web::Path<i32>,
+    object: web::Json<models::NewJob>,
+) -> Result<HttpResponse, Error> {
+    validate_job(&object)?;
+    let job = web::block(move || {
+        let mut conn = pool.get().unwrap();
+        actions::update_job(&mut conn, job_id.into_inner(), object.0)
+    })
+    .await?
+    .map_err(|_e| UserError::InternalError)?
+    .ok_or(UserError::NotFound)?;
+
+    Ok(HttpResponse::Ok().json(job))
+}
+
+/// delete_job removes a job type, unless maintenances still reference it.
+#[delete("/job/{id}")]
+pub async fn delete_job(
+    pool: web::Data<DbPool>,
+    job_id: web::Path<i32>,
+) -> Result<HttpResponse, Error> {
+    let job_id = job_id.into_inner();
+    let deleted = web::block(move || {
+        let mut conn = pool.get().unwrap();
+        conn.transaction(|conn| {
+            if actions::count_maintenances_of_job(conn, job_id)? > 0 {
+                return Ok(None);
+            }
+            actions::delete_job(conn, job_id).map(Some)
+        })
+    })
+    .await?
+    .map_err(|_e: diesel::result::Error| UserError::InternalError)?;
+
+    match deleted {
+        None => Err(UserError::JobInUse.into()),
+        Some(0) => Err(UserError::NotFound.into()),
+        Some(_) => Ok(HttpResponse::NoContent().finish()),
+    }
+}
+
+fn validate_job(job: &models::NewJob) -> Result<(), UserError> {
+    if let Some(template) = &job.template {
+        template::validate_template(template).map_err(UserError::BadRequest)?;
+    }
+    job.validate_parameters_schema()
+        .map_err(UserError::BadRequest)?;
+    if job.max_concurrency.is_some_and(|max| max <= 0) {
+        return Err(UserError::BadRequest(
+            "max_concurrency has to be positive".to_string(),
+        ));
+    }
+    validate_timeouts(job.active_deadline_seconds, job.wait_timeout)?;
+    cluster::validate(job.cluster.as_deref(), job.namespace.as_deref())
+        .map_err(UserError::BadRequest)
+}
+
+fn validate_timeouts(
+    active_deadline_seconds: Option<i32>,
+    wait_timeout: Option<i32>,
+) -> Result<(), UserError> {
+    if active_deadline_seconds.is_some_and(|deadline| deadline <= 0) {
+        return Err(UserError::BadRequest(
+            "active_deadline_seconds has to be positive".to_string(),
+        ));
+    }
+    if wait_timeout.is_some_and(|timeout| timeout <= 0) {
+        return Err(UserError::BadRequest(
+            "wait_timeout has to be positive".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+#[get("/windows")]
+pub async fn get_all_windows(pool: web::Data<DbPool>) -> Result<HttpResponse, Error> {
+    let windows = web::block(move || {
+        let mut conn = pool.get().unwrap();
+        actions::get_all_windows(&mut conn)
+    })
+    .await?
+    .map_err(|_e| UserError::InternalError)?;
+
+    Ok(HttpResponse::Ok().json(windows))
+}
+
+#[get("/window/{id}")]
+pub async fn get_window(
+    pool: web::Data<DbPool>,
+    window_id: web::Path<i32>,
+) -> Result<HttpResponse, Error> {
+    let window = web::block(move || {
+        let mut conn = pool.get().unwrap();
+        actions::find_window_by_id(&mut conn, window_id.into_inner())
+    })
+    .await?
+    .map_err(|_e| UserError::InternalError)?
+    .ok_or(UserError::NotFound)?;
+
+    Ok(HttpResponse::Ok().json(window))
+}
+
+#[post("/window")]
+pub async fn create_window(
+    pool: web::Data<DbPool>,
+    object: web::Json<models::NewMaintenanceWindow>,
+) -> Result<HttpResponse, Error> {
+    validate_window(&object)?;
+    let result = web::block(move || {
+        let mut conn = pool.get().unwrap();
+        actions::insert_new_window(&mut conn, object.0)
+    })
+    .await?
+    .map_err(|_e| UserError::InternalError)?;
+
+    Ok(HttpResponse::Created().json(result))
+}
+
+/// update_window changes a recurring window. Maintenances that have already
+/// been scheduled into an occurrence of the window keep it.
+#[put("/window/{id}")]
+pub async fn update_window(
+    pool: web::Data<DbPool>,
+    window_id: web::Path<i32>,
+    object: web::Json<models::NewMaintenanceWindow>,
+) -> Result<HttpResponse, Error> {
+    validate_window(&object)?;
+    let window = web::block(move || {
+        let mut conn = pool.get().unwrap();
+        actions::update_window(&mut conn, window_id.into_inner(), object.0)
+    })
+    .await?
+    .map_err(|_e| UserError::InternalError)?
+    .ok_or(UserError::NotFound)?;
+
+    Ok(HttpResponse::Ok().json(window))
+}
+
+/// delete_window removes a recurring window, unless maintenances still
+/// reference it.
+#[delete("/window/{id}")]
+pub async fn delete_window(
+    pool: web::Data<DbPool>,
+    window_id: web::Path<i32>,
+) -> Result<HttpResponse, Error> {
+    let window_id = window_id.into_inner();
+    let deleted = web::block(move || {
+        let mut conn = pool.get().unwrap();
+        conn.transaction(|conn| {
+            if actions::count_maintenances_of_window(conn, window_id)? > 0 {
+                return Ok(None);
+            }
+            actions::delete_window(conn, window_id).map(Some)
+        })
+    })
+    .await?
+    .map_err(|_e: diesel::result::Error| UserError::InternalError)?;
+
+    match deleted {
+        None => Err(UserError::WindowInUse.into()),
+        Some(0) => Err(UserError::NotFound.into()),
+        Some(_) => Ok(HttpResponse::NoContent().finish()),
+    }
+}
+
+fn validate_window(window: &models::NewMaintenanceWindow) -> Result<(), UserError> {
+    schedule::validate(&window.schedule, &window.time_zone, window.duration)
+        .map_err(UserError::BadRequest)
 }
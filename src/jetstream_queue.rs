@@ -0,0 +1,518 @@
+//! A `Queue` that hands maintenances to the replicas through a JetStream
+//! work-queue stream.
+//!
+//! The database stays the coordinator, the stream only tells a replica which
+//! maintenance to claim next. Deciding whether a maintenance may run needs
+//! state that the stream does not have: downtime windows and their
+//! recurrences, priorities that age, concurrency limits across job types and
+//! clusters, leases and the run history. All of it has to change in the same
+//! transaction as the status of the maintenance, or two replicas could both
+//! start it. The operations therefore map onto the stream as follows:
+//!
+//! - push stores the maintenance. A message is published only once it is
+//!   due and within its limits, as the stream delivers in order and cannot
+//!   hold a message back until a given time.
+//! - pull fetches messages and claims their maintenances in the store.
+//!   Messages that do not lead to a claim are terminated.
+//! - finishing, failing or timing out an attempt acknowledges the message.
+//!   A retry is not a nak: its backoff and the window it has to fit into are
+//!   kept in the store, which publishes a new message once the retry is due.
+//!
+//! `dispatched_at` in the store keeps the same maintenance from being
+//! published over and over while its message waits to be fetched, and
+//! publishes it again if that message is lost.
+use crate::cluster::Target;
+use crate::database_queue::DatabaseQueue;
+use crate::models::{Job, Maintenance, RunReport};
+use crate::queue::Queue;
+use async_nats::jetstream::{self, consumer::PullConsumer, AckKind};
+use futures::StreamExt;
+use log::debug;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+const STREAM_NAME: &str = "MAINTENANCES";
+const STREAM_SUBJECT: &str = "maintenances.queued";
+const CONSUMER_NAME: &str = "k8s-job-runner";
+/// How long a delivered message may stay unacknowledged before JetStream
+/// hands it to another replica. Matches the lease duration of the store.
+const ACK_WAIT: Duration = Duration::from_secs(60);
+
+/// JetStreamQueue dispatches maintenances through a JetStream work-queue
+/// stream, so that several replicas can share the work. Every message
+/// carries the uuid of a maintenance; the maintenance itself, its status and
+/// its job type are kept in the database store.
+#[derive(Debug)]
+pub struct JetStreamQueue {
+    store: DatabaseQueue,
+    client: async_nats::Client,
+    jetstream: jetstream::Context,
+    consumer: PullConsumer,
+    /// reply subjects of the messages that have been pulled but not yet
+    /// acknowledged, keyed by maintenance uuid.
+    in_flight: Mutex<HashMap<String, String>>,
+}
+
+impl JetStreamQueue {
+    pub async fn connect(
+        nats_url: &str,
+        store: DatabaseQueue,
+    ) -> Result<JetStreamQueue, crate::error::Error> {
+        let client = async_nats::connect(nats_url)
+            .await
+            .map_err(|err| crate::error::Error::Internal(err.to_string()))?;
+        let jetstream = jetstream::new(client.clone());
+
+        let stream = jetstream
+            .get_or_create_stream(jetstream::stream::Config {
+                name: STREAM_NAME.to_string(),
+                subjects: vec![STREAM_SUBJECT.to_string()],
+                retention: jetstream::stream::RetentionPolicy::WorkQueue,
+                ..Default::default()
+            })
+            .await?;
+        let consumer = stream
+            .get_or_create_consumer(
+                CONSUMER_NAME,
+                jetstream::consumer::pull::Config {
+                    durable_name: Some(CONSUMER_NAME.to_string()),
+                    ack_policy: jetstream::consumer::AckPolicy::Explicit,
+                    ack_wait: ACK_WAIT,
+                    ..Default::default()
+                },
+            )
+            .await?;
+
+        Ok(JetStreamQueue {
+            store,
+            client,
+            jetstream,
+            consumer,
+            in_flight: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// dispatch publishes a message for each maintenance that the store
+    /// dispatches. Maintenances whose message could not be published are
+    /// handed back to the store.
+    async fn dispatch(&self, limit: u32) -> Result<(), crate::error::Error> {
+        let mut job_ids = self.store.dispatch(limit)?.into_iter();
+        while let Some(job_id) = job_ids.next() {
+            if let Err(err) = self.publish(job_id.clone()).await {
+                for job_id in std::iter::once(job_id).chain(job_ids) {
+                    self.store.undispatch(job_id)?;
+                }
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    async fn publish(&self, job_id: String) -> Result<(), crate::error::Error> {
+        self.jetstream
+            .publish(STREAM_SUBJECT.to_string(), job_id.into())
+            .await?
+            .await?;
+        Ok(())
+    }
+
+    async fn acknowledge(&self, reply: String, kind: AckKind) -> Result<(), crate::error::Error> {
+        self.client
+            .publish(reply, kind.into())
+            .await
+            .map_err(|err| crate::error::Error::Internal(err.to_string()))
+    }
+
+    /// settle acknowledges the message of a pulled maintenance, removing it
+    /// from the stream.
+    async fn settle(&self, job_id: &str) -> Result<(), crate::error::Error> {
+        let reply = self.in_flight.lock().unwrap().remove(job_id);
+        match reply {
+            Some(reply) => self.acknowledge(reply, AckKind::Ack).await,
+            None => Ok(()),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Queue for JetStreamQueue {
+    /// push stores the maintenance. Its message is published by `pull` once
+    /// it is ready to be claimed.
+    async fn push(&self, job: Maintenance) -> Result<(), crate::error::Error> {
+        self.store.push(job).await
+    }
+
+    /// pull publishes messages for up to `number_of_jobs` maintenances that
+    /// have become ready, then fetches up to `number_of_jobs` messages and
+    /// claims the matching maintenances in the store. Messages that do not
+    /// lead to a claim are dropped, e.g. because the maintenance is already
+    /// running or ended. If it is still waiting, it is dispatched again once
+    /// it can be claimed.
+    async fn pull(
+        &self,
+        number_of_jobs: u32,
+    ) -> Result<Vec<(Maintenance, Job)>, crate::error::Error> {
+        self.dispatch(number_of_jobs).await?;
+        let mut batch = self
+            .consumer
+            .fetch()
+            .max_messages(number_of_jobs as usize)
+            .messages()
+            .await?;
+
+        let mut replies = HashMap::new();
+        while let Some(message) = batch.next().await {
+            let message = message?;
+            let job_id = String::from_utf8_lossy(&message.payload).to_string();
+            if let Some(reply) = message.reply.clone() {
+                replies.insert(job_id, reply);
+            }
+        }
+
+        let jobs = self.store.claim(replies.keys().cloned().collect())?;
+
+        for (maintenance, _) in &jobs {
+            if let Some(reply) = replies.remove(&maintenance.uuid) {
+                self.in_flight
+                    .lock()
+                    .unwrap()
+                    .insert(maintenance.uuid.clone(), reply);
+            }
+        }
+        for (job_id, reply) in replies {
+            debug!("dropping message of maintenance {}", job_id);
+            self.acknowledge(reply, AckKind::Term).await?;
+            self.store.undispatch(job_id)?;
+        }
+
+        Ok(jobs)
+    }
+
+    /// fail_job records the failed attempt in the store and acknowledges the
+    /// message. If the maintenance is going to be retried, or is recurring,
+    /// a new message is published once it is ready again.
+    async fn fail_job(&self, job_id: String, report: RunReport) -> Result<(), crate::error::Error> {
+        self.store.fail_job(job_id.clone(), report).await?;
+        self.settle(&job_id).await
+    }
+
+    async fn time_out_job(
+        &self,
+        job_id: String,
+        report: RunReport,
+    ) -> Result<(), crate::error::Error> {
+        self.store.time_out_job(job_id.clone(), report).await?;
+        self.settle(&job_id).await
+    }
+
+    /// finish_job records the successful run in the store and acknowledges
+    /// the message. A recurring maintenance gets a new message once its next
+    /// occurrence is due.
+    async fn finish_job(
+        &self,
+        job_id: String,
+        report: RunReport,
+    ) -> Result<(), crate::error::Error> {
+        self.store.finish_job(job_id.clone(), report).await?;
+        self.settle(&job_id).await
+    }
+
+    /// renew_lease extends both the lease in the store and the ack deadline
+    /// of the message.
+    async fn renew_lease(&self, job_id: String) -> Result<(), crate::error::Error> {
+        self.store.renew_lease(job_id.clone()).await?;
+        let reply = self.in_flight.lock().unwrap().get(&job_id).cloned();
+        if let Some(reply) = reply {
+            self.acknowledge(reply, AckKind::Progress).await?;
+        }
+        Ok(())
+    }
+
+    async fn find_job(&self, job_id: String) -> Result<Option<Maintenance>, crate::error::Error> {
+        self.store.find_job(job_id).await
+    }
+
+    /// adopt takes over the maintenance in the store. Its message has been
+    /// redelivered when the lease ran out and is dropped by the next pull.
+    async fn adopt(
+        &self,
+        job_id: String,
+    ) -> Result<Option<(Maintenance, Job)>, crate::error::Error> {
+        self.store.adopt(job_id).await
+    }
+
+    /// requeue_expired recovers maintenances of crashed replicas in the
+    /// store. Their old messages are dropped when JetStream redelivers them,
+    /// the requeued maintenances get new ones once they are due.
+    async fn requeue_expired(&self) -> Result<usize, crate::error::Error> {
+        self.store.requeue_expired().await
+    }
+
+    /// cancel cancels the maintenance in the store. The message of a
+    /// queued maintenance is dropped by the next pull.
+    async fn cancel(
+        &self,
+        job_id: String,
+    ) -> Result<Option<(Maintenance, Job)>, crate::error::Error> {
+        let cancelled = self.store.cancel(job_id.clone()).await?;
+        self.settle(&job_id).await?;
+        Ok(cancelled)
+    }
+
+    async fn flag_window_overrun(&self, job_id: String) -> Result<(), crate::error::Error> {
+        self.store.flag_window_overrun(job_id).await
+    }
+
+    async fn targets(&self) -> Result<Vec<Target>, crate::error::Error> {
+        self.store.targets().await
+    }
+}
+
+/// These tests run the queue against a `nats-server` from the PATH, started
+/// with JetStream for every test, and a temporary SQLite store. They are
+/// ignored by default, `cargo test -- --ignored` runs them.
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use super::*;
+    use crate::db::testing::{self, due, TestDb};
+    use crate::models::JobStatus;
+    use crate::queue::ConcurrencyLimits;
+    use diesel::prelude::*;
+    use std::path::PathBuf;
+    use std::process::{Child, Command, Stdio};
+
+    /// Fixture is a nats-server and a store, both removed on drop.
+    struct Fixture {
+        server: Child,
+        url: String,
+        dir: PathBuf,
+        db: TestDb,
+        job_id: i32,
+    }
+
+    impl Fixture {
+        /// start runs a nats-server for the test. The job type of the
+        /// fixture runs at most `max_concurrency` maintenances at once.
+        fn start(max_concurrency: Option<i32>) -> Fixture {
+            let port = std::net::TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap()
+                .port();
+            let dir = std::env::temp_dir().join(format!("k8s-job-runner-{}", ulid::Ulid::new()));
+            let server = Command::new("nats-server")
+                .args(["-js", "-a", "127.0.0.1", "-p", &port.to_string(), "-sd"])
+                .arg(&dir)
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn()
+                .unwrap_or_else(|err| panic!("cannot run nats-server: {}", err));
+            let db = TestDb::new();
+            let job_id = db.insert_job("drain", max_concurrency).id;
+
+            Fixture {
+                server,
+                url: format!("127.0.0.1:{}", port),
+                dir,
+                db,
+                job_id,
+            }
+        }
+
+        /// replica connects a new replica, with a worker id of its own, and
+        /// waits for the server to come up if needed. Its messages have to be
+        /// acknowledged within `ack_wait`.
+        async fn replica(&self, ack_wait: Duration) -> JetStreamQueue {
+            for _ in 0..50 {
+                if let Ok(queue) = self.connect(ack_wait).await {
+                    return queue;
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+            panic!("nats-server at {} did not come up", self.url);
+        }
+
+        async fn connect(&self, ack_wait: Duration) -> Result<JetStreamQueue, crate::error::Error> {
+            // create the consumer up front, `connect` keeps an existing one
+            let client = async_nats::connect(&self.url)
+                .await
+                .map_err(|err| crate::error::Error::Internal(err.to_string()))?;
+            jetstream::new(client)
+                .get_or_create_stream(jetstream::stream::Config {
+                    name: STREAM_NAME.to_string(),
+                    subjects: vec![STREAM_SUBJECT.to_string()],
+                    retention: jetstream::stream::RetentionPolicy::WorkQueue,
+                    ..Default::default()
+                })
+                .await?
+                .get_or_create_consumer(
+                    CONSUMER_NAME,
+                    jetstream::consumer::pull::Config {
+                        durable_name: Some(CONSUMER_NAME.to_string()),
+                        ack_policy: jetstream::consumer::AckPolicy::Explicit,
+                        ack_wait,
+                        ..Default::default()
+                    },
+                )
+                .await?;
+            let store = DatabaseQueue::new(self.db.pool.clone(), ConcurrencyLimits::default());
+            JetStreamQueue::connect(&self.url, store).await
+        }
+
+        /// maintenance returns a maintenance of the fixture's job type that is
+        /// due at `scheduled_for`.
+        fn maintenance(&self, uuid: &str, scheduled_for: chrono::NaiveDateTime) -> Maintenance {
+            let fields = serde_json::json!({ "scheduled_for": scheduled_for });
+            testing::maintenance(uuid, self.job_id, fields)
+        }
+
+        fn status(&self, uuid: &str) -> JobStatus {
+            self.db.find(uuid).status
+        }
+
+        /// set_due makes the maintenance `uuid` due, or lets its lease
+        /// expire if it is running.
+        fn set_due(&self, uuid: &str) {
+            use crate::schema::maintenances::dsl;
+            let past = due();
+            let mut conn = self.db.pool.get().unwrap();
+            diesel::update(dsl::maintenances.filter(dsl::uuid.eq(uuid)))
+                .set((dsl::scheduled_for.eq(past), dsl::lease_expires_at.eq(past)))
+                .execute(&mut conn)
+                .unwrap();
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = self.server.kill();
+            let _ = self.server.wait();
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn tomorrow() -> chrono::NaiveDateTime {
+        chrono::Utc::now().naive_utc() + chrono::Duration::days(1)
+    }
+
+    /// messages returns the number of messages in the stream, once
+    /// acknowledgements that are still on their way have been processed.
+    async fn messages(queue: &JetStreamQueue, expected: u64) -> u64 {
+        let mut stream = queue.jetstream.get_stream(STREAM_NAME).await.unwrap();
+        let mut messages = 0;
+        for _ in 0..20 {
+            messages = stream.info().await.unwrap().state.messages;
+            if messages == expected {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        messages
+    }
+
+    fn uuids(jobs: &[(Maintenance, Job)]) -> Vec<String> {
+        let mut uuids: Vec<String> = jobs.iter().map(|(job, _)| job.uuid.clone()).collect();
+        uuids.sort();
+        uuids
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs nats-server"]
+    async fn maintenances_due_later_are_not_published() {
+        let fixture = Fixture::start(None);
+        let queue = fixture.replica(ACK_WAIT).await;
+        for uuid in ["a", "b", "c"] {
+            let maintenance = fixture.maintenance(uuid, tomorrow());
+            queue.push(maintenance).await.unwrap();
+        }
+
+        assert!(queue.pull(10).await.unwrap().is_empty());
+        assert_eq!(messages(&queue, 0).await, 0);
+        assert_eq!(fixture.status("a"), JobStatus::Pending);
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs nats-server"]
+    async fn due_maintenances_are_published_claimed_and_acknowledged() {
+        let fixture = Fixture::start(None);
+        let queue = fixture.replica(ACK_WAIT).await;
+        queue.push(fixture.maintenance("a", due())).await.unwrap();
+        queue
+            .push(fixture.maintenance("b", tomorrow()))
+            .await
+            .unwrap();
+
+        let jobs = queue.pull(10).await.unwrap();
+        assert_eq!(uuids(&jobs), vec!["a"]);
+        assert_eq!(fixture.status("a"), JobStatus::Running);
+        assert_eq!(messages(&queue, 1).await, 1);
+
+        queue
+            .finish_job("a".to_string(), RunReport::default())
+            .await
+            .unwrap();
+        assert_eq!(fixture.status("a"), JobStatus::Succeeded);
+        assert_eq!(messages(&queue, 0).await, 0);
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs nats-server"]
+    async fn failed_attempts_are_published_again_once_due() {
+        let fixture = Fixture::start(None);
+        let queue = fixture.replica(ACK_WAIT).await;
+        queue.push(fixture.maintenance("a", due())).await.unwrap();
+        assert_eq!(uuids(&queue.pull(10).await.unwrap()), vec!["a"]);
+
+        queue
+            .fail_job("a".to_string(), RunReport::default())
+            .await
+            .unwrap();
+        assert_eq!(fixture.status("a"), JobStatus::Queued);
+        // the retry waits for its backoff
+        assert!(queue.pull(10).await.unwrap().is_empty());
+        assert_eq!(messages(&queue, 0).await, 0);
+
+        fixture.set_due("a");
+        assert_eq!(uuids(&queue.pull(10).await.unwrap()), vec!["a"]);
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs nats-server"]
+    async fn maintenances_over_their_limit_are_not_published() {
+        let fixture = Fixture::start(Some(1));
+        let queue = fixture.replica(ACK_WAIT).await;
+        queue.push(fixture.maintenance("a", due())).await.unwrap();
+        queue.push(fixture.maintenance("b", due())).await.unwrap();
+
+        assert_eq!(queue.pull(10).await.unwrap().len(), 1);
+        assert!(queue.pull(10).await.unwrap().is_empty());
+        assert_eq!(messages(&queue, 1).await, 1);
+    }
+
+    #[actix_web::test]
+    #[ignore = "needs nats-server"]
+    async fn maintenances_of_lost_replicas_are_requeued_and_published_again() {
+        let fixture = Fixture::start(None);
+        let ack_wait = Duration::from_secs(1);
+        let lost = fixture.replica(ack_wait).await;
+        lost.push(fixture.maintenance("a", due())).await.unwrap();
+        let jobs = lost.pull(10).await.unwrap();
+        assert_eq!(uuids(&jobs), vec!["a"]);
+        let lost_worker = jobs[0].0.worker_id.clone();
+        drop(lost);
+
+        let queue = fixture.replica(ack_wait).await;
+        fixture.set_due("a");
+        assert_eq!(queue.requeue_expired().await.unwrap(), 1);
+        assert_eq!(fixture.status("a"), JobStatus::Queued);
+        let jobs = queue.pull(10).await.unwrap();
+        assert_eq!(uuids(&jobs), vec!["a"]);
+        assert_ne!(jobs[0].0.worker_id, lost_worker);
+
+        // the message of the lost replica is redelivered and dropped
+        tokio::time::sleep(ack_wait * 2).await;
+        assert!(queue.pull(10).await.unwrap().is_empty());
+        assert_eq!(fixture.status("a"), JobStatus::Running);
+    }
+}
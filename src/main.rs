@@ -11,25 +11,27 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 mod actions;
+mod cluster;
 mod database_queue;
+mod db;
 mod error;
 mod handlers;
+mod jetstream_queue;
 mod models;
 mod queue;
+mod schedule;
 mod schema;
+mod template;
 mod worker;
 
 use actix_web::{web, App, HttpServer};
 use database_queue::DatabaseQueue;
-use diesel::{
-    r2d2::{self, ConnectionManager},
-    SqliteConnection,
-};
+use db::DbPool;
+use jetstream_queue::JetStreamQueue;
+use log::info;
 use queue::Queue;
 use std::sync::Arc;
 
-type DbPool = r2d2::Pool<ConnectionManager<SqliteConnection>>;
-
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     std::env::set_var("RUST_LOG", "debug");
@@ -40,26 +42,96 @@ async fn main() -> std::io::Result<()> {
     let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
 
     // create db connection pool
-    let manager = ConnectionManager::<SqliteConnection>::new(database_url);
-    let pool = r2d2::Pool::builder()
-        .build(manager)
-        .expect("Failed to create pool.");
+    let pool = db::build_pool(database_url).expect("Failed to create pool.");
+
+    // bring the schema up to date before anything touches the database
+    let mut conn = pool.get().expect("Failed to get a database connection.");
+    for version in db::run_migrations(&mut conn).expect("Failed to run database migrations.") {
+        info!("applied database migration {}", version);
+    }
+    drop(conn);
+    if std::env::args().any(|arg| arg == "--migrate-only") {
+        return Ok(());
+    }
 
-    let queue = Arc::new(DatabaseQueue::new(pool.clone()));
+    let limits = queue::ConcurrencyLimits {
+        global: std::env::var("MAX_CONCURRENCY").ok().map(|max| {
+            max.parse()
+                .ok()
+                .filter(|max| *max > 0)
+                .expect("MAX_CONCURRENCY must be a positive number")
+        }),
+        clusters: std::env::var("CLUSTER_CONCURRENCY")
+            .map(|limits| {
+                queue::ConcurrencyLimits::parse_clusters(&limits)
+                    .unwrap_or_else(|err| panic!("CLUSTER_CONCURRENCY: {}", err))
+            })
+            .unwrap_or_default(),
+    };
+    let store = DatabaseQueue::new(pool.clone(), limits);
+    let queue: Arc<dyn Queue> = match std::env::var("QUEUE_BACKEND").as_deref() {
+        Ok("jetstream") => {
+            let nats_url = std::env::var("NATS_URL").unwrap_or("localhost:4222".to_string());
+            let queue = JetStreamQueue::connect(&nats_url, store)
+                .await
+                .expect("Failed to connect to NATS JetStream.");
+            Arc::new(queue)
+        }
+        _ => Arc::new(store),
+    };
     let worker_queue = queue.clone(); // queue is an Arc pointer, so we only copy the reference
 
-    tokio::spawn(async move { worker::run_worker(worker_queue).await });
+    let window_policy = match std::env::var("WINDOW_OVERRUN_POLICY") {
+        Ok(policy) => policy
+            .parse()
+            .expect("WINDOW_OVERRUN_POLICY must be `flag` or `cancel`"),
+        Err(_) => worker::WindowOverrunPolicy::Flag,
+    };
+
+    let cleanup_policy = match std::env::var("JOB_CLEANUP_POLICY") {
+        Ok(policy) => policy
+            .parse()
+            .expect("JOB_CLEANUP_POLICY must be `background`, `foreground` or `ttl:<seconds>`"),
+        Err(_) => worker::CleanupPolicy::Background,
+    };
+
+    // kubeconfig files of the clusters jobs can target, by cluster name
+    let clients = Arc::new(cluster::ClientPool::new(
+        std::env::var_os("CLUSTER_CONFIG_DIR").map(Into::into),
+    ));
+
+    let worker_clients = clients.clone();
+    tokio::spawn(async move {
+        worker::run_worker(worker_queue, worker_clients, window_policy, cleanup_policy).await
+    });
 
     HttpServer::new(move || {
-        let q: Arc<dyn Queue> = queue.clone();
-        let store_queue: web::Data<dyn Queue> = web::Data::from(q);
+        let store_queue: web::Data<dyn Queue> = web::Data::from(queue.clone());
         App::new()
             .app_data(store_queue)
-            .service(web::scope("/internal").service(handlers::create_maintenance))
+            .app_data(web::Data::new(pool.clone()))
+            .app_data(web::Data::from(clients.clone()))
+            .service(
+                web::scope("/internal")
+                    .service(handlers::create_maintenance)
+                    .service(handlers::cancel_maintenance)
+                    .service(handlers::get_all_jobs)
+                    .service(handlers::get_job)
+                    .service(handlers::create_job)
+                    .service(handlers::update_job)
+                    .service(handlers::delete_job)
+                    .service(handlers::get_all_windows)
+                    .service(handlers::get_window)
+                    .service(handlers::create_window)
+                    .service(handlers::update_window)
+                    .service(handlers::delete_window),
+            )
             .service(
                 web::scope("/external")
                     .service(handlers::get_all_maintenance)
-                    .service(handlers::get_maintenance),
+                    .service(handlers::get_maintenance)
+                    .service(handlers::get_maintenance_runs)
+                    .service(handlers::get_maintenance_run_logs),
             )
     })
     .shutdown_timeout(30)
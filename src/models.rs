@@ -2,8 +2,15 @@ use std::{fmt, str::FromStr};
 
 //use super::schema::maintenances;
 use crate::schema::jobs;
+use crate::schema::maintenance_runs;
+use crate::schema::maintenance_windows;
 use crate::schema::maintenances;
 use chrono::NaiveDateTime;
+use diesel::backend::Backend;
+use diesel::deserialize::{self, FromSql, FromSqlRow};
+use diesel::expression::AsExpression;
+use diesel::serialize::{self, Output, ToSql};
+use diesel::sql_types::Text;
 use diesel::Associations;
 use diesel::Identifiable;
 use diesel::{AsChangeset, Insertable, Queryable, Selectable};
@@ -23,7 +30,7 @@ use serde::{Deserialize, Serialize};
     Debug,
 )]
 #[diesel(table_name = maintenances)]
-#[belongs_to(Job)]
+#[diesel(belongs_to(Job))]
 #[diesel(primary_key(id))]
 pub struct Maintenance {
     #[serde(skip_deserializing)]
@@ -39,11 +46,97 @@ pub struct Maintenance {
     #[serde(skip_deserializing)]
     pub failed_attempts: i32,
     #[serde(skip_deserializing)]
-    pub status: String,
+    pub status: JobStatus,
     pub scheduled_for: Option<NaiveDateTime>,
     pub downtime_window_start: Option<NaiveDateTime>,
     pub downtime_window_end: Option<NaiveDateTime>,
     pub job_id: i32,
+    #[serde(skip_deserializing)]
+    pub worker_id: Option<String>,
+    #[serde(skip_deserializing)]
+    pub lease_expires_at: Option<NaiveDateTime>,
+    /// parameters are handed to the container of the Kubernetes Job, they
+    /// are validated against the `parameters_schema` of the job type.
+    pub parameters: Option<serde_json::Value>,
+    /// window_overrun is set if the maintenance was still running when its
+    /// downtime window closed.
+    #[serde(skip_deserializing)]
+    pub window_overrun: bool,
+    /// window_id refers to a recurring maintenance window. The downtime
+    /// window of the maintenance is then taken from its next occurrence.
+    pub window_id: Option<i32>,
+    /// recurrence is a cron expression (UTC). A recurring maintenance is
+    /// queued again for its next occurrence once a run has ended.
+    pub recurrence: Option<String>,
+    /// active_deadline_seconds and wait_timeout override the ones of the
+    /// job type for this maintenance.
+    pub active_deadline_seconds: Option<i32>,
+    pub wait_timeout: Option<i32>,
+    /// cluster and namespace override the ones of the job type for this
+    /// maintenance.
+    pub cluster: Option<String>,
+    pub namespace: Option<String>,
+    /// priority orders the maintenances that are ready to run, higher
+    /// first. Waiting maintenances gain priority over time, see
+    /// `db::AGED_PRIORITY`.
+    #[serde(default)]
+    pub priority: i32,
+    /// dispatched_at is when a JetStream message was last published for the
+    /// waiting maintenance.
+    #[serde(skip)]
+    pub dispatched_at: Option<NaiveDateTime>,
+}
+
+/// MaintenanceChanges holds the fields that re-submitting a maintenance
+/// replaces. Fields the new submission leaves out are cleared rather than
+/// kept, unlike with the changeset of `Maintenance`.
+#[derive(AsChangeset, Debug)]
+#[diesel(table_name = maintenances)]
+#[diesel(treat_none_as_null = true)]
+pub struct MaintenanceChanges {
+    pub failed_attempts: i32,
+    pub status: JobStatus,
+    pub scheduled_for: Option<NaiveDateTime>,
+    pub downtime_window_start: Option<NaiveDateTime>,
+    pub downtime_window_end: Option<NaiveDateTime>,
+    pub job_id: i32,
+    pub worker_id: Option<String>,
+    pub lease_expires_at: Option<NaiveDateTime>,
+    pub parameters: Option<serde_json::Value>,
+    pub window_overrun: bool,
+    pub window_id: Option<i32>,
+    pub recurrence: Option<String>,
+    pub active_deadline_seconds: Option<i32>,
+    pub wait_timeout: Option<i32>,
+    pub cluster: Option<String>,
+    pub namespace: Option<String>,
+    pub priority: i32,
+    pub dispatched_at: Option<NaiveDateTime>,
+}
+
+impl From<&Maintenance> for MaintenanceChanges {
+    fn from(maintenance: &Maintenance) -> Self {
+        MaintenanceChanges {
+            failed_attempts: maintenance.failed_attempts,
+            status: maintenance.status,
+            scheduled_for: maintenance.scheduled_for,
+            downtime_window_start: maintenance.downtime_window_start,
+            downtime_window_end: maintenance.downtime_window_end,
+            job_id: maintenance.job_id,
+            worker_id: maintenance.worker_id.clone(),
+            lease_expires_at: maintenance.lease_expires_at,
+            parameters: maintenance.parameters.clone(),
+            window_overrun: maintenance.window_overrun,
+            window_id: maintenance.window_id,
+            recurrence: maintenance.recurrence.clone(),
+            active_deadline_seconds: maintenance.active_deadline_seconds,
+            wait_timeout: maintenance.wait_timeout,
+            cluster: maintenance.cluster.clone(),
+            namespace: maintenance.namespace.clone(),
+            priority: maintenance.priority,
+            dispatched_at: maintenance.dispatched_at,
+        }
+    }
 }
 
 #[derive(
@@ -66,39 +159,485 @@ pub struct Job {
     pub version: Option<String>,
     pub docker_image: String,
     pub docker_image_tag: String,
+    /// template is merged onto the spec of every Kubernetes Job created for
+    /// this job type, see `template::build_job`.
+    pub template: Option<serde_json::Value>,
+    /// parameters_schema is an optional JSON Schema for the parameters of
+    /// the maintenances of this job type.
+    pub parameters_schema: Option<serde_json::Value>,
+    /// estimated_duration is the expected runtime in seconds. A maintenance
+    /// is only started if it fits into the rest of its downtime window.
+    pub estimated_duration: Option<i32>,
+    /// active_deadline_seconds is set as `activeDeadlineSeconds` on the
+    /// Kubernetes Job, after which Kubernetes terminates it.
+    pub active_deadline_seconds: Option<i32>,
+    /// wait_timeout is how many seconds the worker waits for the Kubernetes
+    /// Job to complete before it gives up and deletes it.
+    pub wait_timeout: Option<i32>,
+    /// cluster names the Kubernetes cluster the Jobs run in, see
+    /// `cluster::ClientPool`. Without it the runner's own cluster is used.
+    pub cluster: Option<String>,
+    /// namespace the Jobs run in, by default the one of the cluster config.
+    pub namespace: Option<String>,
+    /// max_concurrency bounds the number of maintenances of this job type
+    /// that run at the same time, across all workers.
+    pub max_concurrency: Option<i32>,
 }
 
+impl Job {
+    /// validate_parameters checks the parameters of a maintenance against
+    /// the parameters schema of the job type. Missing parameters are
+    /// validated as an empty object.
+    pub fn validate_parameters(
+        &self,
+        parameters: Option<&serde_json::Value>,
+    ) -> Result<(), String> {
+        let Some(schema) = &self.parameters_schema else {
+            return Ok(());
+        };
+        let validator = jsonschema::validator_for(schema)
+            .map_err(|err| format!("invalid parameters schema: {}", err))?;
+        let empty = serde_json::json!({});
+        let errors: Vec<String> = validator
+            .iter_errors(parameters.unwrap_or(&empty))
+            .map(|err| format!("{} at '{}'", err, err.instance_path))
+            .collect();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("invalid parameters: {}", errors.join(", ")))
+        }
+    }
+}
+
+/// NewJob holds the user supplied fields of a job type, used to create and
+/// update rows in the `jobs` table.
+#[derive(AsChangeset, Insertable, Clone, Serialize, Deserialize, Debug)]
+#[diesel(table_name = jobs)]
+#[diesel(treat_none_as_null = true)]
+pub struct NewJob {
+    pub name: String,
+    pub version: Option<String>,
+    pub docker_image: String,
+    pub docker_image_tag: String,
+    pub template: Option<serde_json::Value>,
+    pub parameters_schema: Option<serde_json::Value>,
+    pub estimated_duration: Option<i32>,
+    pub active_deadline_seconds: Option<i32>,
+    pub wait_timeout: Option<i32>,
+    pub cluster: Option<String>,
+    pub namespace: Option<String>,
+    pub max_concurrency: Option<i32>,
+}
+
+impl NewJob {
+    /// validate_parameters_schema checks that the parameters schema is a
+    /// valid JSON Schema.
+    pub fn validate_parameters_schema(&self) -> Result<(), String> {
+        if let Some(schema) = &self.parameters_schema {
+            jsonschema::meta::validate(schema)
+                .map_err(|err| format!("invalid parameters schema: {}", err))?;
+        }
+        Ok(())
+    }
+}
+
+/// MaintenanceRun records one attempt to run a maintenance: who ran it,
+/// when, as which Kubernetes Job and how it ended.
+#[derive(Queryable, Selectable, PartialEq, Clone, Serialize, Debug)]
+#[diesel(table_name = maintenance_runs)]
+pub struct MaintenanceRun {
+    pub id: i32,
+    pub maintenance_uuid: String,
+    /// attempt counts the attempts of the same occurrence, starting at 1.
+    pub attempt: i32,
+    pub status: JobStatus,
+    pub scheduled_for: Option<NaiveDateTime>,
+    pub worker_id: Option<String>,
+    pub k8s_job_name: Option<String>,
+    pub started_at: Option<NaiveDateTime>,
+    /// finished_at is unset while the attempt is running.
+    pub finished_at: Option<NaiveDateTime>,
+    /// conditions are the final conditions of the Kubernetes Job.
+    pub conditions: Option<serde_json::Value>,
+    pub exit_code: Option<i32>,
+    pub failure_reason: Option<String>,
+    /// logs is the tail of the pod logs, served on its own endpoint.
+    #[serde(skip_serializing)]
+    pub logs: Option<String>,
+}
+
+#[derive(Insertable, Clone, Debug)]
+#[diesel(table_name = maintenance_runs)]
+pub struct NewMaintenanceRun {
+    pub maintenance_uuid: String,
+    pub attempt: i32,
+    pub status: JobStatus,
+    pub scheduled_for: Option<NaiveDateTime>,
+    pub worker_id: Option<String>,
+    pub started_at: Option<NaiveDateTime>,
+}
+
+/// RunReport is what the worker found out about an attempt.
+#[derive(AsChangeset, Clone, Default, Debug)]
+#[diesel(table_name = maintenance_runs)]
+pub struct RunReport {
+    pub k8s_job_name: Option<String>,
+    pub conditions: Option<serde_json::Value>,
+    pub exit_code: Option<i32>,
+    pub failure_reason: Option<String>,
+    pub logs: Option<String>,
+}
+
+/// MaintenanceWindow is a recurring downtime window, e.g. every Sunday from
+/// 02:00 to 04:00 Europe/Berlin.
+#[derive(Queryable, Selectable, Identifiable, PartialEq, Clone, Serialize, Debug)]
+#[diesel(table_name = maintenance_windows)]
+#[diesel(primary_key(id))]
+pub struct MaintenanceWindow {
+    pub id: i32,
+    pub name: String,
+    /// schedule is a cron expression for the start of the window.
+    pub schedule: String,
+    /// time_zone is the IANA time zone the schedule is evaluated in.
+    pub time_zone: String,
+    /// duration is the length of the window in seconds.
+    pub duration: i32,
+}
+
+#[derive(AsChangeset, Insertable, Clone, Deserialize, Debug)]
+#[diesel(table_name = maintenance_windows)]
+pub struct NewMaintenanceWindow {
+    pub name: String,
+    pub schedule: String,
+    #[serde(default = "default_time_zone")]
+    pub time_zone: String,
+    pub duration: i32,
+}
+
+fn default_time_zone() -> String {
+    "UTC".to_string()
+}
+
+/// JobStatus is the state of a maintenance, and of each of its runs.
+///
+/// A maintenance waits in `Pending` (scheduled for later), `WaitingForWindow`
+/// (due once its downtime window opens) or `Queued` (due now, or retrying)
+/// until a worker claims it. A claimed maintenance is `Running` until it
+/// ends as `Succeeded`, `Failed`, `TimedOut` or `Cancelled`, or goes back to
+/// waiting for a retry or its next occurrence. `WindowMissed` ends a
+/// maintenance that could not run within its downtime window. Ended
+/// maintenances can be submitted again.
+#[derive(
+    Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize, AsExpression, FromSqlRow,
+)]
+#[diesel(sql_type = Text)]
+#[serde(rename_all = "snake_case")]
 pub enum JobStatus {
-    NotQueued,
+    #[default]
+    Pending,
     Queued,
+    WaitingForWindow,
     Running,
+    Succeeded,
     Failed,
-    Finished,
+    TimedOut,
+    Cancelled,
+    WindowMissed,
 }
 
-impl fmt::Display for JobStatus {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+impl JobStatus {
+    /// WAITING are the states of maintenances that a worker may claim.
+    pub const WAITING: [JobStatus; 3] = [
+        JobStatus::Pending,
+        JobStatus::Queued,
+        JobStatus::WaitingForWindow,
+    ];
+
+    pub fn is_waiting(self) -> bool {
+        JobStatus::WAITING.contains(&self)
+    }
+
+    /// has_ended reports whether nothing happens to the maintenance anymore
+    /// unless it is submitted again.
+    pub fn has_ended(self) -> bool {
+        !self.is_waiting() && self != JobStatus::Running
+    }
+
+    /// ALL are all states a maintenance can be in.
+    pub const ALL: [JobStatus; 9] = [
+        JobStatus::Pending,
+        JobStatus::Queued,
+        JobStatus::WaitingForWindow,
+        JobStatus::Running,
+        JobStatus::Succeeded,
+        JobStatus::Failed,
+        JobStatus::TimedOut,
+        JobStatus::Cancelled,
+        JobStatus::WindowMissed,
+    ];
+
+    /// as_str returns the name of the state as stored in the database.
+    pub fn as_str(self) -> &'static str {
         match self {
-            JobStatus::NotQueued => write!(f, "not_queued"),
-            JobStatus::Queued => write!(f, "queued"),
-            JobStatus::Running => write!(f, "running"),
-            JobStatus::Failed => write!(f, "failed"),
-            JobStatus::Finished => write!(f, "finished"),
+            JobStatus::Pending => "pending",
+            JobStatus::Queued => "queued",
+            JobStatus::WaitingForWindow => "waiting_for_window",
+            JobStatus::Running => "running",
+            JobStatus::Succeeded => "succeeded",
+            JobStatus::Failed => "failed",
+            JobStatus::TimedOut => "timed_out",
+            JobStatus::Cancelled => "cancelled",
+            JobStatus::WindowMissed => "window_missed",
+        }
+    }
+
+    /// can_transition_to reports whether a maintenance may go from `self`
+    /// to `next` when it is submitted, claimed, ends, misses its window or
+    /// is cancelled. A running maintenance only goes back to waiting when
+    /// it is retried or rescheduled.
+    pub fn can_transition_to(self, next: JobStatus) -> bool {
+        use JobStatus::*;
+        match next {
+            Pending | Queued | WaitingForWindow => self != Running,
+            Running | WindowMissed => self.is_waiting(),
+            Succeeded | Failed | TimedOut => self == Running,
+            Cancelled => !self.has_ended(),
         }
     }
+
+    /// can_retry_to reports whether a maintenance may go from `self` to
+    /// `next` to be attempted again after a failed attempt.
+    pub fn can_retry_to(self, next: JobStatus) -> bool {
+        self == JobStatus::Running && next == JobStatus::Queued
+    }
+
+    /// can_reschedule_to reports whether a maintenance may go from `self`
+    /// to `next` to wait for its next occurrence or downtime window.
+    pub fn can_reschedule_to(self, next: JobStatus) -> bool {
+        (self == JobStatus::Running || self.is_waiting()) && next.is_waiting()
+    }
+
+    /// transition_to returns `next` if a maintenance may go from `self` to
+    /// `next`, else `Error::InvalidTransition`.
+    pub fn transition_to(self, next: JobStatus) -> Result<JobStatus, crate::error::Error> {
+        self.check(next, JobStatus::can_transition_to)
+    }
+
+    /// retry_to is `transition_to` for a retry.
+    pub fn retry_to(self, next: JobStatus) -> Result<JobStatus, crate::error::Error> {
+        self.check(next, JobStatus::can_retry_to)
+    }
+
+    /// reschedule_to is `transition_to` for a reschedule.
+    pub fn reschedule_to(self, next: JobStatus) -> Result<JobStatus, crate::error::Error> {
+        self.check(next, JobStatus::can_reschedule_to)
+    }
+
+    /// sources returns the states `allowed` lets a maintenance go to `next`
+    /// from. Status updates are restricted to rows in these states, so that
+    /// a concurrent change can not be overwritten with an invalid one.
+    pub fn sources(next: JobStatus, allowed: fn(JobStatus, JobStatus) -> bool) -> Vec<JobStatus> {
+        JobStatus::ALL
+            .into_iter()
+            .filter(|from| allowed(*from, next))
+            .collect()
+    }
+
+    fn check(
+        self,
+        next: JobStatus,
+        allowed: fn(JobStatus, JobStatus) -> bool,
+    ) -> Result<JobStatus, crate::error::Error> {
+        if allowed(self, next) {
+            Ok(next)
+        } else {
+            Err(crate::error::Error::InvalidTransition {
+                from: self,
+                to: next,
+            })
+        }
+    }
+}
+
+impl fmt::Display for JobStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
 }
 
 impl FromStr for JobStatus {
-    type Err = ();
+    type Err = String;
 
     fn from_str(input: &str) -> Result<JobStatus, Self::Err> {
         match input {
-            "not_queued" => Ok(JobStatus::NotQueued),
+            "pending" => Ok(JobStatus::Pending),
             "queued" => Ok(JobStatus::Queued),
+            "waiting_for_window" => Ok(JobStatus::WaitingForWindow),
             "running" => Ok(JobStatus::Running),
+            "succeeded" => Ok(JobStatus::Succeeded),
             "failed" => Ok(JobStatus::Failed),
-            "finished" => Ok(JobStatus::Finished),
-            _ => Err(()),
+            "timed_out" => Ok(JobStatus::TimedOut),
+            "cancelled" => Ok(JobStatus::Cancelled),
+            "window_missed" => Ok(JobStatus::WindowMissed),
+            _ => Err(format!("unknown job status {}", input)),
+        }
+    }
+}
+
+impl<DB> ToSql<Text, DB> for JobStatus
+where
+    DB: Backend,
+    str: ToSql<Text, DB>,
+{
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, DB>) -> serialize::Result {
+        self.as_str().to_sql(out)
+    }
+}
+
+impl<DB> FromSql<Text, DB> for JobStatus
+where
+    DB: Backend,
+    String: FromSql<Text, DB>,
+{
+    fn from_sql(bytes: DB::RawValue<'_>) -> deserialize::Result<Self> {
+        Ok(String::from_sql(bytes)?.parse()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::JobStatus::{self, *};
+
+    const ENDED: [JobStatus; 5] = [Succeeded, Failed, TimedOut, Cancelled, WindowMissed];
+
+    #[test]
+    fn waiting_maintenances_can_be_submitted_claimed_missed_and_cancelled() {
+        for from in JobStatus::WAITING {
+            for next in [
+                Pending,
+                Queued,
+                WaitingForWindow,
+                Running,
+                WindowMissed,
+                Cancelled,
+            ] {
+                assert!(from.can_transition_to(next), "{} -> {}", from, next);
+            }
+            for next in [Succeeded, Failed, TimedOut] {
+                assert!(!from.can_transition_to(next), "{} -> {}", from, next);
+            }
+        }
+    }
+
+    #[test]
+    fn running_maintenances_can_only_end() {
+        for next in [Succeeded, Failed, TimedOut, Cancelled] {
+            assert!(Running.can_transition_to(next), "running -> {}", next);
+        }
+        for next in [Pending, Queued, WaitingForWindow, Running, WindowMissed] {
+            assert!(!Running.can_transition_to(next), "running -> {}", next);
+        }
+    }
+
+    #[test]
+    fn ended_maintenances_can_only_be_submitted_again() {
+        for from in ENDED {
+            for next in JobStatus::ALL {
+                assert_eq!(
+                    from.can_transition_to(next),
+                    next.is_waiting(),
+                    "{} -> {}",
+                    from,
+                    next
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn only_running_maintenances_are_retried_and_only_into_the_queue() {
+        for from in JobStatus::ALL {
+            for next in JobStatus::ALL {
+                assert_eq!(
+                    from.can_retry_to(next),
+                    from == Running && next == Queued,
+                    "{} -> {}",
+                    from,
+                    next
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn running_and_waiting_maintenances_are_rescheduled_into_waiting_states() {
+        for from in JobStatus::ALL {
+            for next in JobStatus::ALL {
+                assert_eq!(
+                    from.can_reschedule_to(next),
+                    !from.has_ended() && next.is_waiting(),
+                    "{} -> {}",
+                    from,
+                    next
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn transition_to_rejects_invalid_transitions() {
+        assert_eq!(Queued.transition_to(Running).unwrap(), Running);
+        assert!(matches!(
+            Running.transition_to(Queued),
+            Err(crate::error::Error::InvalidTransition {
+                from: Running,
+                to: Queued
+            })
+        ));
+        assert_eq!(Running.retry_to(Queued).unwrap(), Queued);
+        assert!(Failed.retry_to(Queued).is_err());
+        assert_eq!(
+            Running.reschedule_to(WaitingForWindow).unwrap(),
+            WaitingForWindow
+        );
+        assert!(Cancelled.reschedule_to(Pending).is_err());
+    }
+
+    #[test]
+    fn sources_lists_the_states_a_transition_starts_from() {
+        assert_eq!(
+            JobStatus::sources(Running, JobStatus::can_transition_to),
+            JobStatus::WAITING.to_vec()
+        );
+        assert_eq!(
+            JobStatus::sources(Succeeded, JobStatus::can_transition_to),
+            vec![Running]
+        );
+        assert_eq!(
+            JobStatus::sources(Queued, JobStatus::can_transition_to),
+            vec![
+                Pending,
+                Queued,
+                WaitingForWindow,
+                Succeeded,
+                Failed,
+                TimedOut,
+                Cancelled,
+                WindowMissed
+            ]
+        );
+        assert_eq!(
+            JobStatus::sources(Queued, JobStatus::can_retry_to),
+            vec![Running]
+        );
+    }
+
+    #[test]
+    fn status_names_round_trip() {
+        for status in JobStatus::ALL {
+            assert_eq!(status.as_str().parse::<JobStatus>().unwrap(), status);
         }
+        assert!("done".parse::<JobStatus>().is_err());
     }
 }
@@ -1,18 +1,97 @@
+use crate::cluster::Target;
 use crate::models::Job;
 use crate::models::Maintenance;
-use async_trait;
+use crate::models::RunReport;
+use std::collections::HashMap;
 use std::fmt::Debug;
 
+/// ConcurrencyLimits bound the number of maintenances that run at the same
+/// time across all workers. Job types carry their own limit.
+#[derive(Debug, Clone, Default)]
+pub struct ConcurrencyLimits {
+    /// global bounds the running maintenances overall.
+    pub global: Option<i64>,
+    /// clusters bounds the running maintenances per cluster, `default`
+    /// standing for the runner's own cluster.
+    pub clusters: HashMap<String, i64>,
+}
+
+impl ConcurrencyLimits {
+    /// scope returns the name the limit of `cluster` is configured under.
+    pub fn scope(cluster: Option<&str>) -> &str {
+        cluster.unwrap_or("default")
+    }
+
+    /// parse_clusters parses per cluster limits written as
+    /// `<cluster>=<limit>,...`. The error names the entry that is not valid.
+    pub fn parse_clusters(input: &str) -> Result<HashMap<String, i64>, String> {
+        input
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let (cluster, limit) = entry
+                    .split_once('=')
+                    .ok_or_else(|| format!("{} is not of the form <cluster>=<limit>", entry))?;
+                match limit.trim().parse() {
+                    Ok(limit) if limit > 0 => Ok((cluster.trim().to_string(), limit)),
+                    _ => Err(format!("{} does not have a positive limit", entry)),
+                }
+            })
+            .collect()
+    }
+}
+
 #[async_trait::async_trait]
 pub trait Queue: Send + Sync + Debug {
-    fn push(&self, job: Maintenance) -> Result<(), crate::error::Error>;
-    /// pull fetches at most `number_of_jobs` from the queue.
+    async fn push(&self, job: Maintenance) -> Result<(), crate::error::Error>;
+    /// pull fetches at most `number_of_jobs` from the queue, skipping
+    /// maintenances that would exceed a concurrency limit.
     async fn pull(
         &self,
         number_of_jobs: u32,
     ) -> Result<Vec<(Maintenance, Job)>, crate::error::Error>;
-    async fn delete_job(&self, job_id: String) -> Result<(), crate::error::Error>;
-    async fn fail_job(&self, job_id: String) -> Result<(), crate::error::Error>;
-    async fn finish_job(&self, job_id: String) -> Result<(), crate::error::Error>;
-    async fn clear(&self) -> Result<(), crate::error::Error>;
+    /// fail_job records a failed attempt, described by `report`.
+    async fn fail_job(&self, job_id: String, report: RunReport) -> Result<(), crate::error::Error>;
+    /// time_out_job records an attempt that did not complete in time.
+    async fn time_out_job(
+        &self,
+        job_id: String,
+        report: RunReport,
+    ) -> Result<(), crate::error::Error>;
+    /// finish_job records a successful attempt, described by `report`.
+    async fn finish_job(
+        &self,
+        job_id: String,
+        report: RunReport,
+    ) -> Result<(), crate::error::Error>;
+    /// renew_lease extends the lease this worker holds on a pulled job.
+    /// It fails if the lease has already been lost.
+    async fn renew_lease(&self, job_id: String) -> Result<(), crate::error::Error>;
+    /// find_job returns the maintenance `job_id`, whatever its status.
+    async fn find_job(&self, job_id: String) -> Result<Option<Maintenance>, crate::error::Error>;
+    /// adopt takes over a running job whose lease has expired, e.g. because
+    /// the worker that pulled it was restarted. It returns None if the job
+    /// is not running or still leased to another worker.
+    async fn adopt(
+        &self,
+        job_id: String,
+    ) -> Result<Option<(Maintenance, Job)>, crate::error::Error>;
+    /// requeue_expired puts pulled jobs whose lease has expired back into the
+    /// queue and returns how many were recovered.
+    async fn requeue_expired(&self) -> Result<usize, crate::error::Error>;
+    /// cancel stops a maintenance that has not ended yet and records the
+    /// cancellation of a running attempt. For a running maintenance it
+    /// returns the maintenance as it ran, with its job type, so that the
+    /// caller can delete its Kubernetes Job.
+    async fn cancel(
+        &self,
+        job_id: String,
+    ) -> Result<Option<(Maintenance, Job)>, crate::error::Error>;
+    /// flag_window_overrun records that a job was still running when its
+    /// downtime window closed.
+    async fn flag_window_overrun(&self, job_id: String) -> Result<(), crate::error::Error>;
+    /// targets returns the clusters and namespaces the Kubernetes Jobs of
+    /// the queued maintenances may run in, including the default one.
+    async fn targets(&self) -> Result<Vec<Target>, crate::error::Error>;
 }
@@ -0,0 +1,216 @@
+//! Recurring maintenance windows. A window is described by a cron expression
+//! for its start, the time zone the expression is evaluated in and its
+//! duration, e.g. `0 2 * * Sun`, `Europe/Berlin` and 7200 seconds for every
+//! Sunday from 02:00 to 04:00 local time.
+use crate::models::MaintenanceWindow;
+use chrono::{Duration, NaiveDateTime};
+use chrono_tz::Tz;
+use cron::Schedule;
+use std::str::FromStr;
+
+/// validate checks that `schedule`, `time_zone` and `duration` describe a
+/// window that recurs.
+pub fn validate(schedule: &str, time_zone: &str, duration: i32) -> Result<(), String> {
+    if duration <= 0 {
+        return Err("window duration has to be positive".to_string());
+    }
+    parse_time_zone(time_zone)?;
+    let schedule = parse_schedule(schedule)?;
+    if schedule.upcoming(chrono::Utc).next().is_none() {
+        return Err("window schedule has no upcoming occurrence".to_string());
+    }
+    Ok(())
+}
+
+/// next_window returns start and end of the first occurrence of `window`
+/// that has not ended at `after`. The occurrence may already have started.
+/// All timestamps are UTC, like the ones in the database.
+pub fn next_window(
+    window: &MaintenanceWindow,
+    after: NaiveDateTime,
+) -> Result<(NaiveDateTime, NaiveDateTime), String> {
+    let time_zone = parse_time_zone(&window.time_zone)?;
+    let schedule = parse_schedule(&window.schedule)?;
+    let duration = Duration::seconds(window.duration as i64);
+
+    let start = schedule
+        .after(&(after - duration).and_utc().with_timezone(&time_zone))
+        .next()
+        .ok_or_else(|| format!("window {} has no upcoming occurrence", window.name))?
+        .naive_utc();
+    Ok((start, start + duration))
+}
+
+/// validate_recurrence checks that `recurrence` is a cron expression with an
+/// upcoming occurrence.
+pub fn validate_recurrence(recurrence: &str) -> Result<(), String> {
+    next_occurrence(recurrence, chrono::Utc::now().naive_utc()).map(|_| ())
+}
+
+/// next_occurrence returns the first time after `after` that matches the
+/// cron expression `recurrence`, evaluated in UTC.
+pub fn next_occurrence(recurrence: &str, after: NaiveDateTime) -> Result<NaiveDateTime, String> {
+    parse_schedule(recurrence)?
+        .after(&after.and_utc())
+        .next()
+        .map(|next| next.naive_utc())
+        .ok_or_else(|| "recurrence has no upcoming occurrence".to_string())
+}
+
+/// Names of the days of the week, starting with Sunday as day 0.
+const DAY_NAMES: [&str; 7] = ["SUN", "MON", "TUE", "WED", "THU", "FRI", "SAT"];
+
+/// parse_schedule accepts the usual five field cron expressions as well as
+/// ones with a leading seconds field. Days of the week are numbered as in
+/// standard cron, 0 and 7 being Sunday.
+fn parse_schedule(schedule: &str) -> Result<Schedule, String> {
+    let invalid =
+        |err: &dyn std::fmt::Display| format!("invalid cron expression {}: {}", schedule, err);
+    let mut fields: Vec<String> = schedule.split_whitespace().map(str::to_string).collect();
+    if fields.len() == 5 {
+        fields.insert(0, "0".to_string());
+    }
+    if let Some(days) = fields.get_mut(5) {
+        *days = days_of_week(days).map_err(|err| invalid(&err))?;
+    }
+    Schedule::from_str(&fields.join(" ")).map_err(|err| invalid(&err))
+}
+
+/// days_of_week rewrites a day of week field that uses numbers into day
+/// names. The `cron` crate counts the days from 1 for Sunday, standard cron
+/// from 0, so `1` would be Sunday rather than Monday otherwise.
+fn days_of_week(field: &str) -> Result<String, String> {
+    let days = field
+        .split(',')
+        .map(|item| {
+            if !item.contains(|c: char| c.is_ascii_digit()) {
+                return Ok(vec![item.to_string()]);
+            }
+            let (range, step) = match item.split_once('/') {
+                Some((range, step)) => match step.parse::<usize>() {
+                    Ok(step) if step > 0 => (range, step),
+                    _ => return Err(format!("invalid step in {}", item)),
+                },
+                None => (item, 1),
+            };
+            let (first, last) = match range.split_once('-') {
+                _ if range == "*" => (0, 7),
+                Some((first, last)) => (day_number(first)?, day_number(last)?),
+                None if step > 1 => (day_number(range)?, 7),
+                None => (day_number(range)?, day_number(range)?),
+            };
+            if first > last {
+                return Err(format!("invalid range {}", item));
+            }
+            Ok((first..=last)
+                .step_by(step)
+                .map(|day| DAY_NAMES[day % 7].to_string())
+                .collect())
+        })
+        .collect::<Result<Vec<Vec<String>>, String>>()?;
+    Ok(days.concat().join(","))
+}
+
+/// day_number returns the number of a day of the week, given as a number
+/// from 0 to 7 or by its name.
+fn day_number(day: &str) -> Result<usize, String> {
+    match day.parse::<usize>() {
+        Ok(number) if number <= 7 => Ok(number),
+        Ok(_) => Err(format!("invalid day of week {}", day)),
+        Err(_) => DAY_NAMES
+            .iter()
+            .position(|name| name.eq_ignore_ascii_case(day))
+            .ok_or_else(|| format!("invalid day of week {}", day)),
+    }
+}
+
+fn parse_time_zone(time_zone: &str) -> Result<Tz, String> {
+    Tz::from_str(time_zone).map_err(|_| format!("unknown time zone {}", time_zone))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(timestamp: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    fn window(schedule: &str, time_zone: &str, duration: i32) -> MaintenanceWindow {
+        MaintenanceWindow {
+            id: 1,
+            name: "patch".to_string(),
+            schedule: schedule.to_string(),
+            time_zone: time_zone.to_string(),
+            duration,
+        }
+    }
+
+    #[test]
+    fn days_of_week_count_from_sunday_as_zero() {
+        // 2024-01-01 is a Monday
+        let after = at("2024-01-01 03:00");
+        let next = |recurrence| next_occurrence(recurrence, after).unwrap();
+        assert_eq!(next("0 2 * * 1"), at("2024-01-08 02:00"));
+        assert_eq!(next("0 2 * * 0"), at("2024-01-07 02:00"));
+        assert_eq!(next("0 2 * * 7"), at("2024-01-07 02:00"));
+        assert_eq!(next("0 2 * * Mon"), at("2024-01-08 02:00"));
+        assert_eq!(next("0 2 * * 1-5"), at("2024-01-02 02:00"));
+        assert_eq!(next("0 2 * * 6-7"), at("2024-01-06 02:00"));
+        assert_eq!(next("0 2 * * 3,5"), at("2024-01-03 02:00"));
+        assert_eq!(next("0 2 * * */3"), at("2024-01-03 02:00"));
+        assert_eq!(next("0 0 2 * * 1"), at("2024-01-08 02:00"));
+    }
+
+    #[test]
+    fn invalid_days_of_week_are_rejected() {
+        for recurrence in ["0 2 * * 8", "0 2 * * 5-1", "0 2 * * */0", "0 2 * * Xyz"] {
+            assert!(validate_recurrence(recurrence).is_err(), "{}", recurrence);
+        }
+    }
+
+    #[test]
+    fn next_occurrence_is_strictly_after() {
+        let next = next_occurrence("0 2 * * *", at("2024-01-01 02:00")).unwrap();
+        assert_eq!(next, at("2024-01-02 02:00"));
+    }
+
+    #[test]
+    fn next_window_may_have_started_already() {
+        let sunday_night = window("0 2 * * Sun", "UTC", 7200);
+        let during = next_window(&sunday_night, at("2024-01-07 03:00")).unwrap();
+        assert_eq!(during, (at("2024-01-07 02:00"), at("2024-01-07 04:00")));
+        let after = next_window(&sunday_night, at("2024-01-07 04:00")).unwrap();
+        assert_eq!(after, (at("2024-01-14 02:00"), at("2024-01-14 04:00")));
+    }
+
+    #[test]
+    fn next_window_follows_daylight_saving_time() {
+        let sunday_night = window("0 3 * * 0", "Europe/Berlin", 3600);
+        // 03:00 CET, before clocks go forward on 2024-03-31
+        let winter = next_window(&sunday_night, at("2024-03-20 00:00")).unwrap();
+        assert_eq!(winter, (at("2024-03-24 02:00"), at("2024-03-24 03:00")));
+        // 03:00 CEST
+        let summer = next_window(&sunday_night, at("2024-03-25 00:00")).unwrap();
+        assert_eq!(summer, (at("2024-03-31 01:00"), at("2024-03-31 02:00")));
+        // back to CET after 2024-10-27
+        let autumn = next_window(&sunday_night, at("2024-10-21 00:00")).unwrap();
+        assert_eq!(autumn, (at("2024-10-27 02:00"), at("2024-10-27 03:00")));
+    }
+
+    #[test]
+    fn next_window_skips_a_start_that_does_not_exist() {
+        // 02:30 does not exist in Berlin on 2024-03-31
+        let window = window("30 2 * * *", "Europe/Berlin", 1800);
+        let (start, _) = next_window(&window, at("2024-03-30 02:00")).unwrap();
+        assert_eq!(start, at("2024-04-01 00:30"));
+    }
+
+    #[test]
+    fn validate_checks_duration_and_time_zone() {
+        assert!(validate("0 2 * * 0", "Europe/Berlin", 7200).is_ok());
+        assert!(validate("0 2 * * 0", "Europe/Berlin", 0).is_err());
+        assert!(validate("0 2 * * 0", "Mars/Olympus", 7200).is_err());
+        assert!(validate("0 2 * *", "UTC", 7200).is_err());
+    }
+}
@@ -7,6 +7,42 @@ diesel::table! {
         version -> Nullable<Text>,
         docker_image -> Text,
         docker_image_tag -> Text,
+        template -> Nullable<Json>,
+        parameters_schema -> Nullable<Json>,
+        estimated_duration -> Nullable<Integer>,
+        active_deadline_seconds -> Nullable<Integer>,
+        wait_timeout -> Nullable<Integer>,
+        cluster -> Nullable<Text>,
+        namespace -> Nullable<Text>,
+        max_concurrency -> Nullable<Integer>,
+    }
+}
+
+diesel::table! {
+    maintenance_runs (id) {
+        id -> Integer,
+        maintenance_uuid -> Text,
+        attempt -> Integer,
+        status -> Text,
+        scheduled_for -> Nullable<Timestamp>,
+        worker_id -> Nullable<Text>,
+        k8s_job_name -> Nullable<Text>,
+        started_at -> Nullable<Timestamp>,
+        finished_at -> Nullable<Timestamp>,
+        conditions -> Nullable<Json>,
+        exit_code -> Nullable<Integer>,
+        failure_reason -> Nullable<Text>,
+        logs -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    maintenance_windows (id) {
+        id -> Integer,
+        name -> Text,
+        schedule -> Text,
+        time_zone -> Text,
+        duration -> Integer,
     }
 }
 
@@ -23,12 +59,27 @@ diesel::table! {
         downtime_window_start -> Nullable<Timestamp>,
         downtime_window_end -> Nullable<Timestamp>,
         job_id -> Integer,
+        worker_id -> Nullable<Text>,
+        lease_expires_at -> Nullable<Timestamp>,
+        parameters -> Nullable<Json>,
+        window_overrun -> Bool,
+        window_id -> Nullable<Integer>,
+        recurrence -> Nullable<Text>,
+        active_deadline_seconds -> Nullable<Integer>,
+        wait_timeout -> Nullable<Integer>,
+        cluster -> Nullable<Text>,
+        namespace -> Nullable<Text>,
+        priority -> Integer,
+        dispatched_at -> Nullable<Timestamp>,
     }
 }
 
 diesel::joinable!(maintenances -> jobs (job_id));
+diesel::joinable!(maintenances -> maintenance_windows (window_id));
 
 diesel::allow_tables_to_appear_in_same_query!(
     jobs,
+    maintenance_runs,
+    maintenance_windows,
     maintenances,
 );
@@ -0,0 +1,481 @@
+use crate::models::{Job, Maintenance};
+use chrono::NaiveDateTime;
+use k8s_openapi::api::batch::v1::Job as k8s_job;
+use serde_json::{json, Map, Value};
+
+/// Name of the container that runs a maintenance. Job templates use it to
+/// address the container when setting command, args, env or resources.
+pub const CONTAINER_NAME: &str = "maintenance";
+
+/// Label that marks all Kubernetes Jobs created by the runner.
+pub const LABEL_MANAGED_BY: &str = "app.kubernetes.io/managed-by";
+pub const MANAGED_BY: &str = "k8s-job-runner";
+/// Label and annotation that link a Kubernetes Job to its maintenance.
+pub const LABEL_MAINTENANCE_UUID: &str = "k8s-job-runner/maintenance-uuid";
+const LABEL_JOB_TYPE: &str = "k8s-job-runner/job-type";
+const LABEL_JOB_VERSION: &str = "k8s-job-runner/job-version";
+const ANNOTATION_MAINTENANCE_NAME: &str = "k8s-job-runner/maintenance-name";
+const ANNOTATION_WINDOW_START: &str = "k8s-job-runner/downtime-window-start";
+const ANNOTATION_WINDOW_END: &str = "k8s-job-runner/downtime-window-end";
+
+/// Longest name a Kubernetes Job can have, as it is copied into the
+/// `job-name` label of its pods.
+const MAX_JOB_NAME_LENGTH: usize = 63;
+
+/// job_name returns the name of the Kubernetes Job for the current attempt
+/// of `job`: `mnt-<uuid>-<hash>-<attempt>`. The name is a valid RFC 1123
+/// label however the uuid looks. The uuid is lowercased and shortened if
+/// necessary; the hash over the exact uuid and the scheduled time keeps
+/// names of different maintenances and of different occurrences of a
+/// recurring maintenance apart.
+pub fn job_name(job: &Maintenance) -> String {
+    let attempt = job.failed_attempts + 1;
+    let scheduled_for = job.scheduled_for.map(timestamp).unwrap_or_default();
+    let hash = fnv1a(format!("{}/{}", job.uuid, scheduled_for).as_bytes()) & 0xffff_ffff_ffff;
+    let suffix = format!("-{:012x}-{}", hash, attempt);
+
+    let uuid: String = job
+        .uuid
+        .chars()
+        .map(|c| match c.to_ascii_lowercase() {
+            c @ ('a'..='z' | '0'..='9') => c,
+            _ => '-',
+        })
+        .take(MAX_JOB_NAME_LENGTH - "mnt-".len() - suffix.len())
+        .collect();
+    let uuid = uuid.trim_matches('-');
+    if uuid.is_empty() {
+        format!("mnt{}", suffix)
+    } else {
+        format!("mnt-{}{}", uuid, suffix)
+    }
+}
+
+/// fnv1a is the 64 bit FNV-1a hash. Unlike the hasher of the standard
+/// library it is guaranteed to be stable, which matters because Job names
+/// are computed again after a restart.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// build_job renders the Kubernetes Job `name` that runs `job`. The template
+/// of the job type is merged onto the spec of a minimal Job, the values of
+/// the maintenance are merged on top of that: the image, labels and
+/// annotations that link the Job to its maintenance, environment variables
+/// that tell the container what to work on, and the active deadline of the
+/// maintenance or its job type.
+pub fn build_job(
+    name: &str,
+    job: &Maintenance,
+    job_type: &Job,
+) -> Result<k8s_job, serde_json::Error> {
+    let mut manifest = json!({
+        "apiVersion": "batch/v1",
+        "kind": "Job",
+        "metadata": {},
+        "spec": {
+            "template": {
+                "spec": {
+                    "containers": [{
+                        "name": CONTAINER_NAME,
+                    }],
+                    "restartPolicy": "Never",
+                }
+            }
+        }
+    });
+
+    if let Some(template) = &job_type.template {
+        merge(&mut manifest["spec"], template);
+    }
+
+    let labels = labels(job, job_type);
+    let annotations = annotations(job, job_type);
+    merge(
+        &mut manifest,
+        &json!({
+            "metadata": {
+                "name": name,
+                "labels": labels,
+                "annotations": annotations,
+            },
+            "spec": {
+                "template": {
+                    "metadata": {
+                        "name": name,
+                        "labels": labels,
+                        "annotations": annotations,
+                    },
+                    "spec": {
+                        "containers": [{
+                            "name": CONTAINER_NAME,
+                            "image": image(job_type),
+                            "env": env(job, job_type),
+                        }],
+                    }
+                }
+            }
+        }),
+    );
+    if let Some(deadline) = job
+        .active_deadline_seconds
+        .or(job_type.active_deadline_seconds)
+    {
+        manifest["spec"]["activeDeadlineSeconds"] = deadline.into();
+    }
+
+    serde_json::from_value(manifest)
+}
+
+/// validate_template checks that `template` is a patch for a Job spec that
+/// still results in a valid Kubernetes Job.
+pub fn validate_template(template: &Value) -> Result<(), String> {
+    if !template.is_object() {
+        return Err("job template has to be a JSON object".to_string());
+    }
+
+    let mut manifest = json!({ "spec": { "template": { "spec": { "containers": [] } } } });
+    merge(&mut manifest["spec"], template);
+    serde_json::from_value::<k8s_job>(manifest)
+        .map(|_| ())
+        .map_err(|err| format!("invalid job template: {}", err))
+}
+
+fn labels(job: &Maintenance, job_type: &Job) -> Map<String, Value> {
+    let mut labels = Map::new();
+    labels.insert(LABEL_MANAGED_BY.to_string(), MANAGED_BY.into());
+    labels.insert(
+        LABEL_MAINTENANCE_UUID.to_string(),
+        label_value(&job.uuid).into(),
+    );
+    labels.insert(
+        LABEL_JOB_TYPE.to_string(),
+        label_value(&job_type.name).into(),
+    );
+    if let Some(version) = &job_type.version {
+        labels.insert(LABEL_JOB_VERSION.to_string(), label_value(version).into());
+    }
+    labels
+}
+
+/// annotations carry the exact values, labels may have been shortened to
+/// satisfy the Kubernetes syntax for label values.
+fn annotations(job: &Maintenance, job_type: &Job) -> Map<String, Value> {
+    let mut annotations = Map::new();
+    annotations.insert(LABEL_MAINTENANCE_UUID.to_string(), job.uuid.clone().into());
+    annotations.insert(LABEL_JOB_TYPE.to_string(), job_type.name.clone().into());
+    if let Some(version) = &job_type.version {
+        annotations.insert(LABEL_JOB_VERSION.to_string(), version.clone().into());
+    }
+    if let Some(name) = &job.name {
+        annotations.insert(ANNOTATION_MAINTENANCE_NAME.to_string(), name.clone().into());
+    }
+    if let Some(start) = job.downtime_window_start {
+        annotations.insert(ANNOTATION_WINDOW_START.to_string(), timestamp(start).into());
+    }
+    if let Some(end) = job.downtime_window_end {
+        annotations.insert(ANNOTATION_WINDOW_END.to_string(), timestamp(end).into());
+    }
+    annotations
+}
+
+fn env(job: &Maintenance, job_type: &Job) -> Vec<Value> {
+    let optional = |value: Option<String>| value.unwrap_or_default();
+    [
+        ("MAINTENANCE_UUID", job.uuid.clone()),
+        ("MAINTENANCE_NAME", optional(job.name.clone())),
+        ("MAINTENANCE_JOB_TYPE", job_type.name.clone()),
+        (
+            "MAINTENANCE_JOB_VERSION",
+            optional(job_type.version.clone()),
+        ),
+        (
+            "MAINTENANCE_DOWNTIME_WINDOW_START",
+            optional(job.downtime_window_start.map(timestamp)),
+        ),
+        (
+            "MAINTENANCE_DOWNTIME_WINDOW_END",
+            optional(job.downtime_window_end.map(timestamp)),
+        ),
+        (
+            "MAINTENANCE_PARAMETERS",
+            optional(job.parameters.as_ref().map(|p| p.to_string())),
+        ),
+    ]
+    .into_iter()
+    .map(|(name, value)| json!({ "name": name, "value": value }))
+    .collect()
+}
+
+/// timestamp formats a UTC timestamp of the database as RFC 3339.
+fn timestamp(time: NaiveDateTime) -> String {
+    time.and_utc().to_rfc3339()
+}
+
+/// label_value turns `value` into a valid label value: at most 63
+/// alphanumeric characters, '-', '_' or '.', starting and ending with an
+/// alphanumeric character.
+fn label_value(value: &str) -> String {
+    let value: String = value
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => c,
+            _ => '-',
+        })
+        .take(63)
+        .collect();
+    value
+        .trim_matches(|c: char| !c.is_ascii_alphanumeric())
+        .to_string()
+}
+
+fn image(job_type: &Job) -> String {
+    if job_type.docker_image_tag.is_empty() {
+        job_type.docker_image.clone()
+    } else {
+        format!("{}:{}", job_type.docker_image, job_type.docker_image_tag)
+    }
+}
+
+/// merge applies `patch` to `target` like a JSON merge patch (RFC 7386),
+/// except that lists of named objects, such as containers, env or volumes,
+/// are merged entry by entry on their `name`.
+fn merge(target: &mut Value, patch: &Value) {
+    match patch {
+        Value::Object(patch) => {
+            if !target.is_object() {
+                *target = json!({});
+            }
+            let target = target.as_object_mut().unwrap();
+            for (key, value) in patch {
+                if value.is_null() {
+                    target.remove(key);
+                } else {
+                    merge(target.entry(key.as_str()).or_insert(Value::Null), value);
+                }
+            }
+        }
+        Value::Array(patch)
+            if is_named_list(patch) && target.as_array().is_some_and(|t| is_named_list(t)) =>
+        {
+            let target = target.as_array_mut().unwrap();
+            for item in patch {
+                match target
+                    .iter_mut()
+                    .find(|entry| entry["name"] == item["name"])
+                {
+                    Some(entry) => merge(entry, item),
+                    None => target.push(item.clone()),
+                }
+            }
+        }
+        _ => *target = patch.clone(),
+    }
+}
+
+fn is_named_list(list: &[Value]) -> bool {
+    list.iter()
+        .all(|item| item.get("name").is_some_and(Value::is_string))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn maintenance(uuid: &str, scheduled_for: Option<&str>, failed_attempts: i32) -> Maintenance {
+        let mut job: Maintenance = serde_json::from_value(json!({
+            "job_id": 1,
+            "scheduled_for": scheduled_for,
+        }))
+        .unwrap();
+        job.uuid = uuid.to_string();
+        job.failed_attempts = failed_attempts;
+        job
+    }
+
+    /// is_label reports whether `name` is an RFC 1123 label, as Kubernetes
+    /// requires for Job names.
+    fn is_label(name: &str) -> bool {
+        !name.is_empty()
+            && name.len() <= MAX_JOB_NAME_LENGTH
+            && name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+            && !name.starts_with('-')
+            && !name.ends_with('-')
+    }
+
+    #[test]
+    fn job_name_contains_lowercased_uuid_and_attempt() {
+        let name = job_name(&maintenance(
+            "3F2504E0-4F89-11D3-9A0C-0305E82C3301",
+            Some("2023-07-01T02:00:00"),
+            2,
+        ));
+        assert!(
+            name.starts_with("mnt-3f2504e0-4f89-11d3-9a0c-0305e82c3301-"),
+            "{}",
+            name
+        );
+        assert!(name.ends_with("-3"), "{}", name);
+        assert!(is_label(&name), "{}", name);
+    }
+
+    #[test]
+    fn job_name_is_a_label_whatever_the_uuid() {
+        let long = "a".repeat(100);
+        let dashes = "x-".repeat(40);
+        let uuids = [
+            "",
+            "-",
+            "!!!",
+            "._/",
+            "ÄÖÜ",
+            "メンテナンス",
+            "Server_01.Example.COM",
+            "-leading-and-trailing-",
+            long.as_str(),
+            dashes.as_str(),
+        ];
+        for uuid in uuids {
+            for failed_attempts in [0, 4, i32::MAX - 1] {
+                let name = job_name(&maintenance(uuid, None, failed_attempts));
+                assert!(is_label(&name), "{:?}: {}", uuid, name);
+            }
+        }
+        assert!(job_name(&maintenance("!!!", None, 0)).starts_with("mnt-"));
+        assert!(job_name(&maintenance("ÄÖÜ", None, 0)).starts_with("mnt-"));
+    }
+
+    #[test]
+    fn job_names_differ_between_maintenances_attempts_and_occurrences() {
+        let name = |uuid: &str, scheduled_for: Option<&str>, failed_attempts| {
+            job_name(&maintenance(uuid, scheduled_for, failed_attempts))
+        };
+        let at = Some("2023-07-01T02:00:00");
+        let long = "a".repeat(100);
+
+        assert_eq!(name("abc", at, 0), name("abc", at, 0));
+        assert_ne!(name("abc", at, 0), name("ABC", at, 0));
+        assert_ne!(name("a.b", at, 0), name("a_b", at, 0));
+        assert_ne!(name("abc", at, 0), name("abc", at, 1));
+        assert_ne!(
+            name("abc", at, 0),
+            name("abc", Some("2023-07-08T02:00:00"), 0)
+        );
+        assert_ne!(name(&long, at, 0), name(&format!("{}b", long), at, 0));
+    }
+
+    #[test]
+    fn fnv1a_is_stable() {
+        assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(fnv1a(b"foobar"), 0x8594_4171_f739_67e8);
+    }
+
+    #[test]
+    fn label_value_keeps_valid_values() {
+        assert_eq!(label_value("drain-node_v1.2"), "drain-node_v1.2");
+        assert_eq!(label_value("Server01"), "Server01");
+        assert_eq!(label_value(""), "");
+    }
+
+    #[test]
+    fn label_value_replaces_and_trims_invalid_characters() {
+        assert_eq!(label_value("Ünïcode value"), "n-code-value");
+        assert_eq!(label_value("--drain--"), "drain");
+        assert_eq!(label_value("a/b:c"), "a-b-c");
+        assert_eq!(label_value("!!!"), "");
+    }
+
+    #[test]
+    fn label_value_is_at_most_63_characters() {
+        assert_eq!(label_value(&"a".repeat(100)), "a".repeat(63));
+        // shortening must not leave a trailing separator
+        let value = format!("{}-{}", "a".repeat(62), "b".repeat(10));
+        assert_eq!(label_value(&value), "a".repeat(62));
+    }
+
+    #[test]
+    fn merge_patches_objects_and_deletes_nulls() {
+        let mut target = json!({ "a": 1, "b": { "c": 2, "d": 3 }, "g": "x" });
+        merge(
+            &mut target,
+            &json!({ "b": { "c": null, "e": 4 }, "f": 5, "g": { "h": 6 } }),
+        );
+        assert_eq!(
+            target,
+            json!({ "a": 1, "b": { "d": 3, "e": 4 }, "f": 5, "g": { "h": 6 } })
+        );
+    }
+
+    #[test]
+    fn merge_merges_named_lists_by_name() {
+        let mut target = json!([
+            { "name": "maintenance", "image": "busybox", "args": ["a"] },
+            { "name": "sidecar", "image": "proxy" },
+        ]);
+        merge(
+            &mut target,
+            &json!([
+                { "name": "maintenance", "args": ["b"], "image": null },
+                { "name": "logger", "image": "fluentd" },
+            ]),
+        );
+        assert_eq!(
+            target,
+            json!([
+                { "name": "maintenance", "args": ["b"] },
+                { "name": "sidecar", "image": "proxy" },
+                { "name": "logger", "image": "fluentd" },
+            ])
+        );
+    }
+
+    #[test]
+    fn merge_replaces_other_lists() {
+        let mut target = json!({ "args": ["a", "b"], "env": [{ "name": "A" }] });
+        merge(
+            &mut target,
+            &json!({ "args": ["c"], "env": [{ "value": "unnamed" }] }),
+        );
+        assert_eq!(
+            target,
+            json!({ "args": ["c"], "env": [{ "value": "unnamed" }] })
+        );
+    }
+
+    #[test]
+    fn build_job_applies_template_below_maintenance_values() {
+        let job_type: Job = serde_json::from_value(json!({
+            "id": 1,
+            "name": "drain",
+            "docker_image": "registry/drain",
+            "docker_image_tag": "1.0",
+            "template": {
+                "backoffLimit": 2,
+                "template": { "spec": { "containers": [
+                    { "name": "maintenance", "command": ["/bin/drain"], "image": "other" },
+                    { "name": "sidecar", "image": "proxy" },
+                ] } },
+            },
+        }))
+        .unwrap();
+        let job = maintenance("Host-01", Some("2023-07-01T02:00:00"), 0);
+        let name = job_name(&job);
+
+        let manifest = serde_json::to_value(build_job(&name, &job, &job_type).unwrap()).unwrap();
+        assert_eq!(manifest["metadata"]["name"], json!(name));
+        assert_eq!(
+            manifest["metadata"]["labels"][LABEL_MAINTENANCE_UUID],
+            json!("Host-01")
+        );
+        assert_eq!(manifest["spec"]["backoffLimit"], json!(2));
+        let containers = &manifest["spec"]["template"]["spec"]["containers"];
+        assert_eq!(containers[0]["image"], json!("registry/drain:1.0"));
+        assert_eq!(containers[0]["command"], json!(["/bin/drain"]));
+        assert_eq!(containers[1]["name"], json!("sidecar"));
+    }
+}
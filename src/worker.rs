@@ -1,151 +1,753 @@
+use crate::cluster::{ClientPool, Target};
 use crate::error;
-use crate::models::{Job, Maintenance};
+use crate::models::{Job, JobStatus, Maintenance, RunReport};
 use crate::queue::Queue;
-use futures::{stream, StreamExt};
+use crate::template;
+use futures::StreamExt;
 use k8s_openapi::api::batch::v1::Job as k8s_job;
+use k8s_openapi::api::core::v1::Pod;
 use kube::{
-    api::{Api, DeleteParams, PostParams},
-    runtime::wait::{await_condition, Condition},
-    Client,
+    api::{Api, DeleteParams, ListParams, LogParams, PostParams},
+    runtime::{watcher, WatchStreamExt},
+    ResourceExt,
+};
+use log::{debug, error, info, warn};
+use std::collections::{HashMap, HashSet};
+use std::{
+    future::Future,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
-use log::{debug, error, info};
-use std::{sync::Arc, time::Duration};
-use tokio;
 
+/// Upper bound for the number of maintenances that run at the same time.
 const CONCURRENCY: usize = 50;
+/// How often the worker pulls new maintenances and retries the Kubernetes
+/// Jobs it could not reconcile or adopt yet.
+const TICK_INTERVAL: Duration = Duration::from_secs(10);
+/// How often the worker renews the leases of the running maintenances and
+/// checks their timeouts and downtime windows. This has to be well below
+/// the lease duration of the queue.
+const SUPERVISE_INTERVAL: Duration = Duration::from_secs(10);
+/// Upper bound for a single call to the Kubernetes API. It keeps an
+/// unreachable cluster from holding up the tick for minutes.
+const KUBE_TIMEOUT: Duration = Duration::from_secs(20);
+/// How long the worker waits for a Kubernetes Job if neither the maintenance
+/// nor its job type configure a wait timeout or an active deadline.
+const DEFAULT_WAIT_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+/// Extra time on top of the active deadline for Kubernetes to report that
+/// the Job has failed.
+const DEADLINE_GRACE_PERIOD: Duration = Duration::from_secs(60);
+/// How much of the pod logs is kept for every run.
+const LOG_TAIL_LINES: i64 = 2000;
+const MAX_LOG_BYTES: usize = 64 * 1024;
+
+/// WindowOverrunPolicy decides what happens to a maintenance that is still
+/// running when its downtime window closes.
+#[derive(Debug, Clone, Copy)]
+pub enum WindowOverrunPolicy {
+    /// let the maintenance finish, but flag it
+    Flag,
+    /// delete the Kubernetes Job and fail the attempt
+    Cancel,
+}
+
+impl FromStr for WindowOverrunPolicy {
+    type Err = ();
+
+    fn from_str(input: &str) -> Result<WindowOverrunPolicy, Self::Err> {
+        match input {
+            "flag" => Ok(WindowOverrunPolicy::Flag),
+            "cancel" => Ok(WindowOverrunPolicy::Cancel),
+            _ => Err(()),
+        }
+    }
+}
+
+/// CleanupPolicy decides how the Kubernetes Job of a maintenance is removed
+/// once the maintenance is done with it.
+#[derive(Debug, Clone, Copy)]
+pub enum CleanupPolicy {
+    /// delete the Job, its pods are removed in the background
+    Background,
+    /// delete the Job, it is only gone once its pods are removed
+    Foreground,
+    /// set `ttlSecondsAfterFinished` and leave finished Jobs to Kubernetes
+    Ttl(i32),
+}
+
+impl FromStr for CleanupPolicy {
+    type Err = ();
+
+    fn from_str(input: &str) -> Result<CleanupPolicy, Self::Err> {
+        match input.split_once(':') {
+            Some(("ttl", seconds)) => match seconds.parse() {
+                Ok(seconds) if seconds >= 0 => Ok(CleanupPolicy::Ttl(seconds)),
+                _ => Err(()),
+            },
+            None if input == "background" => Ok(CleanupPolicy::Background),
+            None if input == "foreground" => Ok(CleanupPolicy::Foreground),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Running is a maintenance whose Kubernetes Job has been created and is
+/// waiting for the watcher to report its outcome.
+#[derive(Debug)]
+struct Running {
+    job: Maintenance,
+    target: Target,
+    name: String,
+    /// deadline is when the worker stops waiting for the Kubernetes Job.
+    deadline: Instant,
+    window_overrun: bool,
+}
+
+/// Worker runs the maintenances it pulls from the queue as Kubernetes Jobs.
+/// It does not wait on the Jobs one by one: one watcher per cluster and
+/// namespace reports the outcome of the runner's Jobs there. A task of its
+/// own renews the leases of the running maintenances and enforces timeouts
+/// and downtime windows, so that a slow cluster cannot let the leases run
+/// out, while a periodic tick pulls new maintenances. A worker runs at most
+/// `CONCURRENCY` maintenances at once; the queue holds back maintenances
+/// that would exceed the limits of their job type, their cluster or the
+/// whole runner.
+struct Worker {
+    queue: Arc<dyn Queue>,
+    clients: Arc<ClientPool>,
+    window_policy: WindowOverrunPolicy,
+    cleanup: CleanupPolicy,
+    /// running maintenances, keyed by uuid
+    running: Mutex<HashMap<String, Running>>,
+    /// targets that have a watcher
+    watched: Mutex<HashSet<Target>>,
+    /// targets whose Kubernetes Jobs could not be listed on startup, e.g.
+    /// because their cluster was unreachable. They are retried every tick.
+    unreconciled: Mutex<Vec<Target>>,
+    /// Kubernetes Jobs found on startup whose maintenances are still leased
+    /// to another worker. They are adopted once that lease runs out.
+    unclaimed: Mutex<Vec<(Target, k8s_job)>>,
+}
+
+pub async fn run_worker(
+    queue: Arc<dyn Queue>,
+    clients: Arc<ClientPool>,
+    window_policy: WindowOverrunPolicy,
+    cleanup: CleanupPolicy,
+) {
+    let worker = Arc::new(Worker {
+        queue,
+        clients,
+        window_policy,
+        cleanup,
+        running: Mutex::new(HashMap::new()),
+        watched: Mutex::new(HashSet::new()),
+        unreconciled: Mutex::new(Vec::new()),
+        unclaimed: Mutex::new(Vec::new()),
+    });
+    tokio::spawn(worker.clone().supervise_running());
+    worker.reconcile().await;
 
-pub async fn run_worker(queue: Arc<dyn Queue>) {
     loop {
-        let jobs = match queue.pull(CONCURRENCY as u32).await {
-            Ok(jobs) => jobs,
+        worker.reconcile_pending().await;
+        worker.adopt_unclaimed().await;
+        match worker.queue.requeue_expired().await {
+            Ok(0) => {}
+            Ok(requeued) => info!("run_worker: requeued {} jobs with expired lease", requeued),
+            Err(err) => error!("run_worker: requeueing expired jobs: {}", err),
+        }
+        worker.pull().await;
+        tokio::time::sleep(TICK_INTERVAL).await;
+    }
+}
+
+impl Worker {
+    async fn jobs(&self, target: &Target) -> Result<Api<k8s_job>, error::Error> {
+        bounded(self.clients.api(target)).await
+    }
+
+    /// reconcile matches the Kubernetes Jobs of the runner that exist at
+    /// startup with their maintenances, in every cluster and namespace the
+    /// queue knows of. Jobs of running maintenances are adopted, whether
+    /// they are still running or have completed while no worker was
+    /// watching. Jobs that belong to no running maintenance are deleted.
+    async fn reconcile(self: &Arc<Self>) {
+        let targets = match self.queue.targets().await {
+            Ok(targets) => targets,
             Err(err) => {
-                error!("run_worker: pulling jobs: {}", err);
-                tokio::time::sleep(Duration::from_secs(10)).await;
-                Vec::new()
+                error!("reconcile: looking up clusters and namespaces: {}", err);
+                vec![Target::default()]
             }
         };
+        *self.unreconciled.lock().unwrap() = targets;
+        self.reconcile_pending().await;
+    }
 
-        let number_of_jobs = jobs.len();
-        if number_of_jobs > 0 {
-            debug!("Fetched {} jobs", number_of_jobs);
-        }
-        let cloned_queue = queue.clone();
-        tokio::spawn(async move {
-            stream::iter(jobs)
-                .for_each_concurrent(CONCURRENCY, |job| async {
-                    let job_id = job.0.uuid.to_string();
-                    let res = match handle_job(job.0.clone(), job.1).await {
-                        Ok(_) => cloned_queue.finish_job(job_id).await,
-                        Err(err) => {
-                            info!("run_worker: handling job({}): {}", job_id, &err);
-                            cloned_queue.fail_job(job_id).await
-                        }
-                    };
-                    match res {
-                        Ok(_) => {
-                            if let Some(err) = cleanup_job(job.0).await.err() {
-                                error!("error deleting k8s job {}", err)
-                            }
-                        }
-                        Err(err) => {
-                            error!("run_worker: deleting / failing job: {}", err);
-                        }
-                    }
-                })
-                .await;
+    /// reconcile_pending reconciles the targets whose Jobs could not be
+    /// listed yet.
+    async fn reconcile_pending(self: &Arc<Self>) {
+        let pending = std::mem::take(&mut *self.unreconciled.lock().unwrap());
+        for target in pending {
+            self.watch(&target);
+            let jobs = match self.jobs(&target).await {
+                Ok(api) => bounded(api.list(&managed_jobs())).await,
+                Err(err) => Err(err),
+            };
+            let jobs = match jobs {
+                Ok(jobs) => jobs.items,
+                Err(err) => {
+                    error!("reconcile: listing k8s jobs in {}: {}", target, err);
+                    self.unreconciled.lock().unwrap().push(target);
+                    continue;
+                }
+            };
+            info!("reconcile: found {} k8s jobs in {}", jobs.len(), target);
+            for job in jobs {
+                if self.reconcile_job(&target, &job).await {
+                    self.unclaimed.lock().unwrap().push((target.clone(), job));
+                }
+            }
+        }
+    }
+
+    /// adopt_unclaimed retries the Jobs that `reconcile` could not adopt yet.
+    async fn adopt_unclaimed(self: &Arc<Self>) {
+        let unclaimed = std::mem::take(&mut *self.unclaimed.lock().unwrap());
+        for (target, job) in unclaimed {
+            if self.reconcile_job(&target, &job).await {
+                self.unclaimed.lock().unwrap().push((target, job));
+            }
+        }
+    }
+
+    /// reconcile_job adopts or deletes a single Kubernetes Job. It returns
+    /// true if the maintenance of the Job is leased to another worker, so
+    /// that the Job has to be looked at again later.
+    async fn reconcile_job(self: &Arc<Self>, target: &Target, job: &k8s_job) -> bool {
+        let name = job.name_any();
+        let job_id = job
+            .annotations()
+            .get(template::LABEL_MAINTENANCE_UUID)
+            .cloned();
+        let maintenance = match job_id.clone() {
+            Some(job_id) => match self.queue.find_job(job_id).await {
+                Ok(maintenance) => maintenance,
+                Err(err) => {
+                    error!("reconcile: looking up k8s job {}: {}", name, err);
+                    return true;
+                }
+            },
+            None => None,
+        };
+        let is_current = maintenance.is_some_and(|maintenance| {
+            maintenance.status == JobStatus::Running && template::job_name(&maintenance) == name
         });
-        tokio::time::sleep(Duration::from_secs(10)).await;
+        let (Some(job_id), true) = (job_id, is_current) else {
+            info!("reconcile: cleaning up orphaned k8s job {}", name);
+            self.cleanup(target, &name, job_result(job).is_some()).await;
+            return false;
+        };
+
+        match self.queue.adopt(job_id.clone()).await {
+            Ok(Some((maintenance, job_type))) => {
+                info!("reconcile: adopting k8s job {} of job({})", name, job_id);
+                self.adopt(target, maintenance, job_type, job).await;
+                false
+            }
+            Ok(None) => true,
+            Err(err) => {
+                error!("reconcile: adopting job({}): {}", job_id, err);
+                true
+            }
+        }
     }
-}
 
-async fn handle_job(job: Maintenance, job_type: Job) -> Result<(), crate::error::Error> {
-    println!("{:?} JOB Started", job.uuid);
-    let client = Client::try_default().await?;
-    let jobs: Api<k8s_job> = Api::default_namespaced(client);
-
-    info!("creating k8s job: {:?}", job.uuid);
-    let name = format!("lifecycle_mgmt_{}", job.uuid);
-    let data = match serde_json::from_value(serde_json::json!({
-        "apiVersion": "batch/v1",
-        "kind": "Job",
-        "metadata": {
-            "name": name,
-        },
-        "spec": {
-            "template": {
-                "metadata": {
-                    "name": name
-                },
-                "spec": {
-                    "containers": [{
-                        "name": "empty",
-                        "image": job_type.docker_image
-                    }],
-                    "restartPolicy": "Never",
+    /// adopt registers the Kubernetes Job of an adopted maintenance as
+    /// running. The time it has already run counts against its timeout.
+    /// The Job stays in `target`, where it was found, even if the
+    /// maintenance has been moved to another cluster or namespace since.
+    async fn adopt(
+        self: &Arc<Self>,
+        target: &Target,
+        maintenance: Maintenance,
+        job_type: Job,
+        job: &k8s_job,
+    ) {
+        let elapsed = job
+            .creation_timestamp()
+            .and_then(|created| (chrono::Utc::now() - created.0).to_std().ok())
+            .unwrap_or_default();
+        let timeout = wait_timeout(&maintenance, &job_type).saturating_sub(elapsed);
+        let name = job.name_any();
+        let job_id = maintenance.uuid.clone();
+        self.running.lock().unwrap().insert(
+            job_id.clone(),
+            Running {
+                job: maintenance,
+                target: target.clone(),
+                name: name.clone(),
+                deadline: Instant::now() + timeout,
+                window_overrun: false,
+            },
+        );
+
+        // the watcher may have reported the outcome before the maintenance
+        // was registered, so look at the current state of the Job once more
+        let job = match self.jobs(target).await {
+            Ok(api) => bounded(api.get_opt(&name)).await,
+            Err(err) => Err(err),
+        };
+        match job {
+            Ok(Some(job)) => self.observe(target, job),
+            Ok(None) => {
+                let running = self.running.lock().unwrap().remove(&job_id);
+                if let Some(running) = running {
+                    let err = error::Error::Internal("k8s job was deleted".to_string());
+                    self.complete(running, None, Err(err)).await;
                 }
             }
+            Err(err) => warn!("reconcile: fetching k8s job {}: {}", name, err),
+        }
+    }
+
+    /// pull fetches as many maintenances as there are free slots and starts
+    /// them.
+    async fn pull(self: &Arc<Self>) {
+        let free = CONCURRENCY.saturating_sub(self.running.lock().unwrap().len());
+        if free == 0 {
+            return;
         }
-    })) {
-        Ok(j) => j,
-        Err(e) => {
-            error!("error creating k8s job: {}", e);
-            return Err(error::Error::Internal(e.to_string()));
+        let jobs = match self.queue.pull(free as u32).await {
+            Ok(jobs) => jobs,
+            Err(err) => {
+                error!("run_worker: pulling jobs: {}", err);
+                return;
+            }
+        };
+        if !jobs.is_empty() {
+            debug!("Fetched {} jobs", jobs.len());
         }
-    };
-    jobs.create(&PostParams::default(), &data).await?;
+        futures::future::join_all(
+            jobs.into_iter()
+                .map(|(job, job_type)| self.start(job, job_type)),
+        )
+        .await;
+    }
 
-    info!("Waiting for job to complete");
-    let cond = await_condition(jobs.clone(), &name, is_job_completed_or_failed());
+    /// start creates the Kubernetes Job of a pulled maintenance. The
+    /// maintenance is registered as running before, so that the watcher
+    /// cannot miss the outcome of a quick Job.
+    async fn start(self: &Arc<Self>, job: Maintenance, job_type: Job) {
+        let job_id = job.uuid.clone();
+        let name = template::job_name(&job);
+        let target = Target::of(&job, &job_type);
+        info!("creating k8s job {} in {}: {:?}", name, target, job_id);
+        self.watch(&target);
+        let mut data = match template::build_job(&name, &job, &job_type) {
+            Ok(data) => data,
+            Err(e) => {
+                error!("error creating k8s job: {}", e);
+                let running = Running {
+                    job,
+                    target,
+                    name,
+                    deadline: Instant::now(),
+                    window_overrun: false,
+                };
+                self.complete(running, None, Err(error::Error::Internal(e.to_string())))
+                    .await;
+                return;
+            }
+        };
 
-    let result = match tokio::time::timeout(std::time::Duration::from_secs(20), cond).await {
-        Ok(j) => j,
-        Err(e) => {
-            jobs.delete(&name, &DeleteParams::background()).await?;
-            return Err(error::Error::Internal(e.to_string()));
+        if let (CleanupPolicy::Ttl(ttl), Some(spec)) = (self.cleanup, data.spec.as_mut()) {
+            spec.ttl_seconds_after_finished = Some(ttl);
+        }
+        let deadline = Instant::now() + wait_timeout(&job, &job_type);
+        self.running.lock().unwrap().insert(
+            job_id.clone(),
+            Running {
+                job,
+                target: target.clone(),
+                name,
+                deadline,
+                window_overrun: false,
+            },
+        );
+        let created = match self.jobs(&target).await {
+            Ok(api) => bounded(api.create(&PostParams::default(), &data)).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = created {
+            if let Some(running) = self.running.lock().unwrap().remove(&job_id) {
+                let worker = self.clone();
+                tokio::spawn(async move { worker.complete(running, None, Err(err)).await });
+            }
         }
-    }?;
+    }
+
+    /// watch starts a watcher for `target` unless it has one already.
+    fn watch(self: &Arc<Self>, target: &Target) {
+        if self.watched.lock().unwrap().insert(target.clone()) {
+            tokio::spawn(self.clone().watch_target(target.clone()));
+        }
+    }
 
-    if let Some(k8s_job) = result {
-        if let Some(status) = k8s_job.status {
-            if let Some(failed) = status.failed {
-                if failed > 0 {
-                    jobs.delete(&name, &DeleteParams::background()).await?;
-                    return Err(error::Error::Internal("job failed".to_string()));
+    /// watch_target follows the Kubernetes Jobs of the runner in `target`
+    /// and completes the running maintenances as their Jobs finish.
+    async fn watch_target(self: Arc<Self>, target: Target) {
+        let jobs = loop {
+            match self.jobs(&target).await {
+                Ok(jobs) => break jobs,
+                Err(err) => {
+                    error!("watching k8s jobs in {}: {}", target, err);
+                    tokio::time::sleep(TICK_INTERVAL).await;
                 }
             }
+        };
+        let mut events = watcher(jobs, managed_jobs())
+            .backoff(watcher::default_backoff())
+            .boxed();
+        while let Some(event) = events.next().await {
+            match event {
+                Ok(watcher::Event::Applied(job)) => self.observe(&target, job),
+                Ok(watcher::Event::Restarted(jobs)) => {
+                    jobs.into_iter().for_each(|job| self.observe(&target, job))
+                }
+                Ok(watcher::Event::Deleted(job)) => {
+                    if let Some(running) = self.take(&target, &job) {
+                        let worker = self.clone();
+                        let err = error::Error::Internal("k8s job was deleted".to_string());
+                        tokio::spawn(async move { worker.complete(running, None, Err(err)).await });
+                    }
+                }
+                Err(err) => warn!("watching k8s jobs in {}: {}", target, err),
+            }
         }
     }
-    info!("{:?} JOB finished", job.uuid);
-    Ok(())
-}
 
-async fn cleanup_job(job: Maintenance) -> Result<(), crate::error::Error> {
-    let client = Client::try_default().await?;
-    let jobs: Api<k8s_job> = Api::default_namespaced(client);
+    /// observe completes the maintenance of `job` if the Job has finished.
+    fn observe(self: &Arc<Self>, target: &Target, job: k8s_job) {
+        let Some(result) = job_result(&job) else {
+            return;
+        };
+        if let Some(running) = self.take(target, &job) {
+            let worker = self.clone();
+            tokio::spawn(async move { worker.complete(running, Some(job), result).await });
+        }
+    }
 
-    jobs.delete(&job.uuid, &DeleteParams::default())
-        .await?
-        .map_left(|o| debug!("Deleting job: {:?}", o.status))
-        .map_right(|s| debug!("Deleted job: {:?}", s));
+    /// take removes the running maintenance that `job` in `target` belongs
+    /// to.
+    fn take(&self, target: &Target, job: &k8s_job) -> Option<Running> {
+        let job_id = job.annotations().get(template::LABEL_MAINTENANCE_UUID)?;
+        let mut running = self.running.lock().unwrap();
+        let entry = running.get(job_id)?;
+        if entry.name != job.name_any() || entry.target != *target {
+            return None;
+        }
+        running.remove(job_id)
+    }
 
-    Ok(())
-}
+    /// supervise_running calls `supervise` every `SUPERVISE_INTERVAL`, apart
+    /// from the tick.
+    async fn supervise_running(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(SUPERVISE_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            self.supervise().await;
+        }
+    }
 
-#[must_use]
-fn is_job_completed_or_failed() -> impl Condition<k8s_job> {
-    |obj: Option<&k8s_job>| {
-        if let Some(job) = &obj {
-            if let Some(s) = &job.status {
-                if let Some(conds) = &s.conditions {
-                    if let Some(pcond) = conds.iter().find(|c| c.type_ == "Complete") {
-                        return pcond.status == "True";
-                    }
-                    if let Some(pcond) = conds.iter().find(|c| c.type_ == "Failed") {
-                        return pcond.status == "True";
+    /// supervise renews the leases of the running maintenances and stops
+    /// waiting for the ones that ran out of time or out of their downtime
+    /// window. It only talks to the queue; Kubernetes Jobs are deleted and
+    /// inspected in tasks of their own.
+    async fn supervise(self: &Arc<Self>) {
+        let now = Instant::now();
+        let current_time = chrono::Utc::now().naive_utc();
+        let mut renew = Vec::new();
+        let mut overrun = Vec::new();
+        let mut aborted = Vec::new();
+        {
+            let mut running = self.running.lock().unwrap();
+            for (job_id, entry) in running.iter_mut() {
+                let window_closed = entry
+                    .job
+                    .downtime_window_end
+                    .is_some_and(|end| end <= current_time);
+                if now >= entry.deadline {
+                    let err = error::Error::TimedOut(format!(
+                        "k8s job {} did not complete in time",
+                        entry.name
+                    ));
+                    aborted.push((job_id.clone(), err));
+                } else if window_closed && !entry.window_overrun {
+                    match self.window_policy {
+                        WindowOverrunPolicy::Flag => {
+                            warn!(
+                                "job({}) is still running after its downtime window closed",
+                                job_id
+                            );
+                            entry.window_overrun = true;
+                            overrun.push(job_id.clone());
+                        }
+                        WindowOverrunPolicy::Cancel => {
+                            warn!("cancelling job({}), its downtime window closed", job_id);
+                            let err = error::Error::Internal("downtime window closed".to_string());
+                            aborted.push((job_id.clone(), err));
+                        }
                     }
+                } else {
+                    renew.push(job_id.clone());
                 }
             }
         }
-        false
+
+        let mut lost = Vec::new();
+        for job_id in renew.into_iter().chain(overrun.iter().cloned()) {
+            match self.queue.renew_lease(job_id.clone()).await {
+                Ok(()) => {}
+                Err(error::Error::NotFound(_)) => lost.push(job_id),
+                Err(err) => error!("renewing lease of job({}): {}", job_id, err),
+            }
+        }
+        // the maintenance was cancelled or taken over by another worker
+        // after its lease expired, either way its Job has to go
+        for job_id in lost {
+            let running = self.running.lock().unwrap().remove(&job_id);
+            if let Some(running) = running {
+                info!(
+                    "job({}) is no longer leased to this worker, deleting k8s job {}",
+                    job_id, running.name
+                );
+                let worker = self.clone();
+                tokio::spawn(async move {
+                    let params = DeleteParams::foreground();
+                    delete_job(&worker.clients, &running.target, &running.name, &params).await
+                });
+            }
+        }
+        for job_id in overrun {
+            if let Err(err) = self.queue.flag_window_overrun(job_id.clone()).await {
+                error!("flagging window overrun of job({}): {}", job_id, err);
+            }
+        }
+        for (job_id, err) in aborted {
+            let running = self.running.lock().unwrap().remove(&job_id);
+            if let Some(running) = running {
+                let worker = self.clone();
+                tokio::spawn(async move { worker.complete(running, None, Err(err)).await });
+            }
+        }
+    }
+
+    /// complete records the outcome of a maintenance in the queue. The pod
+    /// is inspected first, the Kubernetes Job is cleaned up afterwards.
+    async fn complete(
+        &self,
+        running: Running,
+        job: Option<k8s_job>,
+        result: Result<(), error::Error>,
+    ) {
+        let job_id = running.job.uuid.clone();
+        let finished = job.is_some();
+        let mut report = RunReport {
+            k8s_job_name: Some(running.name.clone()),
+            ..Default::default()
+        };
+        if let Some(status) = job.and_then(|job| job.status) {
+            report.conditions = status
+                .conditions
+                .as_ref()
+                .and_then(|conds| serde_json::to_value(conds).ok());
+            report.failure_reason = status
+                .conditions
+                .iter()
+                .flatten()
+                .find(|c| c.type_ == "Failed" && c.status == "True")
+                .and_then(|c| c.message.clone().or(c.reason.clone()));
+        }
+        match bounded(self.clients.api::<Pod>(&running.target)).await {
+            Ok(pods) => inspect_pod(pods, &running.name, &mut report).await,
+            Err(err) => warn!("inspecting pod of k8s job {}: {}", running.name, err),
+        }
+
+        let res = match result {
+            Ok(_) => {
+                info!("{:?} JOB finished", job_id);
+                self.queue.finish_job(job_id.clone(), report).await
+            }
+            Err(err @ error::Error::TimedOut(_)) => {
+                info!("run_worker: handling job({}): {}", job_id, &err);
+                report.failure_reason.get_or_insert(err.to_string());
+                self.queue.time_out_job(job_id.clone(), report).await
+            }
+            Err(err) => {
+                info!("run_worker: handling job({}): {}", job_id, &err);
+                report.failure_reason.get_or_insert(err.to_string());
+                self.queue.fail_job(job_id.clone(), report).await
+            }
+        };
+        match res {
+            Ok(()) => {}
+            // the Job was deleted because the maintenance was cancelled
+            Err(error::Error::InvalidTransition {
+                from: JobStatus::Cancelled,
+                ..
+            }) => info!("job({}) was cancelled", job_id),
+            Err(err) => error!("run_worker: finishing / failing job: {}", err),
+        }
+        self.cleanup(&running.target, &running.name, finished).await;
+    }
+
+    /// cleanup deletes the Kubernetes Job `name` in `target` together with
+    /// its pods. Under the ttl policy a finished Job is left for Kubernetes
+    /// to remove, only Jobs that are still running are deleted.
+    async fn cleanup(&self, target: &Target, name: &str, finished: bool) {
+        let params = match self.cleanup {
+            CleanupPolicy::Background => DeleteParams::background(),
+            CleanupPolicy::Foreground => DeleteParams::foreground(),
+            CleanupPolicy::Ttl(_) if finished => return,
+            CleanupPolicy::Ttl(_) => DeleteParams::background(),
+        };
+        delete_job(&self.clients, target, name, &params).await;
+    }
+}
+
+/// delete_job deletes the Kubernetes Job `name` in `target`. A Job that is
+/// already gone is fine, other errors are logged.
+pub async fn delete_job(clients: &ClientPool, target: &Target, name: &str, params: &DeleteParams) {
+    let jobs = match bounded(clients.api::<k8s_job>(target)).await {
+        Ok(jobs) => jobs,
+        Err(err) => {
+            error!("error deleting k8s job {}: {}", name, err);
+            return;
+        }
+    };
+    match tokio::time::timeout(KUBE_TIMEOUT, jobs.delete(name, params)).await {
+        Ok(Ok(res)) => {
+            res.map_left(|o| debug!("Deleting job: {:?}", o.status))
+                .map_right(|s| debug!("Deleted job: {:?}", s));
+        }
+        Ok(Err(kube::Error::Api(err))) if err.code == 404 => {}
+        Ok(Err(err)) => error!("error deleting k8s job {}: {}", name, err),
+        Err(_) => error!("error deleting k8s job {}: request timed out", name),
+    }
+}
+
+/// bounded waits for a call to the Kubernetes API for at most
+/// `KUBE_TIMEOUT`. Running out of time is an internal error rather than
+/// `TimedOut`, which stands for a maintenance that took too long.
+async fn bounded<T, E>(call: impl Future<Output = Result<T, E>>) -> Result<T, error::Error>
+where
+    error::Error: From<E>,
+{
+    match tokio::time::timeout(KUBE_TIMEOUT, call).await {
+        Ok(result) => result.map_err(error::Error::from),
+        Err(_) => Err(error::Error::Internal("k8s request timed out".to_string())),
+    }
+}
+
+/// managed_jobs selects the Kubernetes Jobs created by the runner.
+fn managed_jobs() -> ListParams {
+    ListParams::default().labels(&format!(
+        "{}={}",
+        template::LABEL_MANAGED_BY,
+        template::MANAGED_BY
+    ))
+}
+
+/// job_result returns the outcome of a Kubernetes Job, or None while it is
+/// still running.
+fn job_result(job: &k8s_job) -> Option<Result<(), error::Error>> {
+    let conds = job.status.as_ref()?.conditions.as_ref()?;
+    let is_true = |type_: &str| {
+        conds
+            .iter()
+            .find(|c| c.type_ == type_ && c.status == "True")
+    };
+    if is_true("Complete").is_some() {
+        return Some(Ok(()));
+    }
+    let failed = is_true("Failed")?;
+    if failed.reason.as_deref() == Some("DeadlineExceeded") {
+        return Some(Err(error::Error::TimedOut(
+            "active deadline exceeded".to_string(),
+        )));
+    }
+    Some(Err(error::Error::Internal("job failed".to_string())))
+}
+
+/// wait_timeout returns how long to wait for the Kubernetes Job of `job`:
+/// the wait timeout of the maintenance or its job type, else a little more
+/// than the active deadline, else `DEFAULT_WAIT_TIMEOUT`.
+fn wait_timeout(job: &Maintenance, job_type: &Job) -> Duration {
+    let seconds = |value: i32| Duration::from_secs(value.max(0) as u64);
+    if let Some(timeout) = job.wait_timeout.or(job_type.wait_timeout) {
+        return seconds(timeout);
+    }
+    job.active_deadline_seconds
+        .or(job_type.active_deadline_seconds)
+        .map(|deadline| seconds(deadline) + DEADLINE_GRACE_PERIOD)
+        .unwrap_or(DEFAULT_WAIT_TIMEOUT)
+}
+
+/// inspect_pod records exit code and logs of the maintenance container in
+/// the latest pod of the Kubernetes Job `name`. This has to happen before
+/// the Job is deleted, which takes its pods along.
+async fn inspect_pod(pods: Api<Pod>, name: &str, report: &mut RunReport) {
+    let pod = match bounded(pods.list(&ListParams::default().labels(&format!("job-name={}", name))))
+        .await
+    {
+        Ok(list) => list
+            .items
+            .into_iter()
+            .max_by_key(|pod| pod.metadata.creation_timestamp.as_ref().map(|time| time.0)),
+        Err(err) => {
+            warn!("listing pods of k8s job {}: {}", name, err);
+            return;
+        }
+    };
+    let Some(pod) = pod else {
+        return;
+    };
+
+    report.exit_code = pod
+        .status
+        .as_ref()
+        .and_then(|status| status.container_statuses.as_ref())
+        .and_then(|statuses| {
+            statuses
+                .iter()
+                .find(|status| status.name == template::CONTAINER_NAME)
+        })
+        .and_then(|status| status.state.as_ref())
+        .and_then(|state| state.terminated.as_ref())
+        .map(|terminated| terminated.exit_code);
+
+    let pod_name = pod.metadata.name.unwrap_or_default();
+    let params = LogParams {
+        container: Some(template::CONTAINER_NAME.to_string()),
+        tail_lines: Some(LOG_TAIL_LINES),
+        ..Default::default()
+    };
+    match bounded(pods.logs(&pod_name, &params)).await {
+        Ok(logs) => report.logs = Some(truncate_logs(logs)),
+        Err(err) => warn!("fetching logs of pod {}: {}", pod_name, err),
+    }
+}
+
+/// truncate_logs keeps the last `MAX_LOG_BYTES` of `logs`.
+fn truncate_logs(logs: String) -> String {
+    if logs.len() <= MAX_LOG_BYTES {
+        return logs;
+    }
+    let mut start = logs.len() - MAX_LOG_BYTES;
+    while !logs.is_char_boundary(start) {
+        start += 1;
     }
+    format!("[truncated]\n{}", &logs[start..])
 }